@@ -0,0 +1,210 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use image::{DynamicImage, ImageFormat, Rgba, RgbaImage};
+use imageproc::{
+    drawing::{draw_filled_rect_mut, draw_hollow_rect_mut, draw_text_mut, text_size},
+    rect::Rect,
+};
+use rusttype::{Font, Scale};
+use tracing::instrument;
+
+use crate::{
+    error::PredictionError,
+    face::ImageFaces,
+    identity::{closest_identity, Identity},
+    metric::FaceMatcher,
+    post_processor::Bbox,
+    search_faces,
+};
+
+pub static ANNOTATED_FOLDER_NAME: &str = "annotated";
+
+static MATCH_COLOR: Rgba<u8> = Rgba([0, 200, 0, 255]);
+static NO_MATCH_COLOR: Rgba<u8> = Rgba([220, 0, 0, 255]);
+static LABEL_TEXT_COLOR: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+/// Label of a detected face, its best match is `None` if there is nothing to match against.
+#[derive(Clone, Debug)]
+pub struct FaceLabel {
+    /// Bounding box in raw image pixel coordinates.
+    pub bbox: Bbox,
+    pub confidence: f32,
+    pub best_match: Option<FaceMatch>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FaceMatch {
+    pub identity: String,
+    pub distance: f32,
+    pub is_match: bool,
+}
+
+/// Draws the detected faces with their labels onto the original images.
+pub struct Annotator {
+    pub matcher: FaceMatcher,
+    font: Option<Font<'static>>,
+}
+
+impl Annotator {
+    pub fn new(matcher: FaceMatcher) -> Annotator {
+        Annotator {
+            matcher,
+            font: None,
+        }
+    }
+
+    /// Load the TrueType font used for the labels, without a font only the boxes are drawn.
+    pub fn with_font(mut self, font_path: &Path) -> Result<Self, PredictionError> {
+        let font = Font::try_from_vec(fs::read(font_path)?)
+            .ok_or_else(|| PredictionError::InvalidFont(font_path.to_path_buf()))?;
+        self.font = Some(font);
+        Ok(self)
+    }
+
+    /// Label every face of `image_faces` with its closest face in `gallery_images`, faces of the
+    /// image itself are not matched.
+    pub fn label_faces(
+        &self,
+        image_faces: &ImageFaces,
+        gallery_images: &[ImageFaces],
+    ) -> Vec<FaceLabel> {
+        image_faces
+            .faces
+            .iter()
+            .map(|face| FaceLabel {
+                bbox: face.bbox,
+                confidence: face.confidence,
+                best_match: search_faces(&face.embedding, gallery_images, &self.matcher)
+                    .into_iter()
+                    .find(|result| result.image_path != image_faces.image_path)
+                    .map(|result| FaceMatch {
+                        identity: identity(&result.image_path),
+                        distance: result.distance,
+                        is_match: result.is_match,
+                    }),
+            })
+            .collect()
+    }
+
+    /// Label every face of `image_faces` with its closest enrolled identity, or as unknown if it
+    /// does not match any.
+    pub fn identify_faces(
+        &self,
+        image_faces: &ImageFaces,
+        identities: &BTreeMap<String, Identity>,
+    ) -> Vec<FaceLabel> {
+        image_faces
+            .faces
+            .iter()
+            .map(|face| FaceLabel {
+                bbox: face.bbox,
+                confidence: face.confidence,
+                best_match: closest_identity(identities, &face.embedding, &self.matcher).map(
+                    |identity_match| FaceMatch {
+                        identity: identity_match.label().to_string(),
+                        distance: identity_match.distance,
+                        is_match: identity_match.is_match,
+                    },
+                ),
+            })
+            .collect()
+    }
+
+    /// Draw `labels` onto the image at `image_path` and save it to `output_path` in the format of
+    /// the original image.
+    #[instrument(level = "debug", skip(self, labels))]
+    pub fn annotate(
+        &self,
+        image_path: &Path,
+        labels: &[FaceLabel],
+        output_path: &Path,
+    ) -> Result<(), PredictionError> {
+        let raw_image = image::open(image_path).map_err(PredictionError::image(image_path))?;
+        let format = ImageFormat::from_path(image_path).unwrap_or(ImageFormat::Png);
+
+        let mut frame = raw_image.to_rgba8();
+        for label in labels {
+            self.draw_label(&mut frame, label);
+        }
+        // keep the color type of the original, e.g. JPEG has no alpha channel
+        let annotated = if raw_image.color().has_alpha() {
+            DynamicImage::ImageRgba8(frame)
+        } else {
+            DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(frame).to_rgb8())
+        };
+
+        if let Some(output_folder) = output_path.parent() {
+            fs::create_dir_all(output_folder)?;
+        }
+        annotated
+            .save_with_format(output_path, format)
+            .map_err(PredictionError::image(output_path))
+    }
+
+    fn draw_label(&self, frame: &mut RgbaImage, label: &FaceLabel) {
+        let color = match &label.best_match {
+            Some(face_match) if face_match.is_match => MATCH_COLOR,
+            _ => NO_MATCH_COLOR,
+        };
+
+        // scale lines and text with the image so they stay readable on large photos
+        let line_width = (frame.width().max(frame.height()) / 400).max(2) as i32;
+        let (x, y) = (label.bbox[0] as i32, label.bbox[1] as i32);
+        let (width, height) = (
+            (label.bbox[2] - label.bbox[0]).max(1.0) as u32,
+            (label.bbox[3] - label.bbox[1]).max(1.0) as u32,
+        );
+        for offset in 0..line_width {
+            let rect = Rect::at(x - offset, y - offset)
+                .of_size(width + 2 * offset as u32, height + 2 * offset as u32);
+            draw_hollow_rect_mut(frame, rect, color);
+        }
+
+        let font = match &self.font {
+            Some(font) => font,
+            None => return,
+        };
+        let text = match &label.best_match {
+            Some(face_match) => format!(
+                "{} {:.2} ({:.2})",
+                face_match.identity, face_match.distance, label.confidence
+            ),
+            None => format!("{:.2}", label.confidence),
+        };
+        let scale = Scale::uniform((frame.height() as f32 / 40.0).max(12.0));
+        let (text_width, text_height) = text_size(scale, font, &text);
+        let padding = line_width;
+        let background_height = text_height + 2 * padding;
+        // above the box, or inside it if the box touches the top of the image
+        let text_y = match y - line_width - background_height {
+            text_y if text_y >= 0 => text_y,
+            _ => y,
+        };
+        draw_filled_rect_mut(
+            frame,
+            Rect::at(x - line_width, text_y).of_size(
+                (text_width + 2 * padding).max(1) as u32,
+                background_height as u32,
+            ),
+            color,
+        );
+        draw_text_mut(
+            frame,
+            LABEL_TEXT_COLOR,
+            x - line_width + padding,
+            text_y + padding,
+            scale,
+            font,
+            &text,
+        );
+    }
+}
+
+/// Identity shown for a gallery image.
+fn identity(image_path: &Path) -> String {
+    image_path
+        .file_stem()
+        .unwrap_or(image_path.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
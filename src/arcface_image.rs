@@ -1,11 +1,14 @@
+use std::borrow::Cow;
+
 use image::{
-    imageops::{crop_imm, FilterType},
+    error::{ParameterError, ParameterErrorKind},
     DynamicImage, ImageError, RgbImage,
 };
 
 use crate::{
+    arcface_predictor::ARC_FACE_INPUT_SIZE,
+    face_alignment::{align_face, Landmarks},
     post_processor::Bbox,
-    ultra_predictor::{ULTRA_INPUT_HEIGHT, ULTRA_INPUT_WIDTH},
 };
 
 pub struct ArcFaceImage {
@@ -13,63 +16,41 @@ pub struct ArcFaceImage {
 }
 
 impl ArcFaceImage {
-    pub fn new(raw_image: DynamicImage, bbox: Bbox) -> Result<ArcFaceImage, ImageError> {
-        let cropped_raw_image = crop_ultra_image_raw(raw_image, bbox)?;
-        let raw_image = DynamicImage::from(cropped_raw_image)
-            .resize_to_fill(128, 128, FilterType::Triangle)
-            .to_rgb8();
-
-        return Ok(ArcFaceImage { image: raw_image });
+    /// Align the face described by `landmarks` (in raw image pixel coordinates) to the ArcFace
+    /// template. `rgb_image` is the raw image, see `as_rgb_image`.
+    pub fn new(rgb_image: &RgbImage, landmarks: &Landmarks) -> Result<ArcFaceImage, ImageError> {
+        let image =
+            align_face(rgb_image, landmarks, ARC_FACE_INPUT_SIZE as u32).ok_or_else(|| {
+                ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::Generic(
+                    "unable to align face, landmarks are degenerate".to_string(),
+                )))
+            })?;
+
+        Ok(ArcFaceImage { image })
     }
 }
 
-// crop image returning sub_image, assumes bbox is found of ULTRA_INPUT_WIDTH x ULTRA_INPUT_HEIGHT version of image
-fn crop_ultra_image_raw(image: DynamicImage, bbox: Bbox) -> Result<RgbImage, ImageError> {
-    let width: f32 = image.width() as f32;
-    let height: f32 = image.height() as f32;
-
-    let aspect_ratio_raw_image = width / height;
-    let aspect_ratio_ultra = ULTRA_INPUT_WIDTH as f32 / ULTRA_INPUT_HEIGHT as f32;
-
-    let (x_tl, y_tl, x_br, y_br): (f32, f32, f32, f32) =
-        if aspect_ratio_raw_image > aspect_ratio_ultra {
-            let scaled_width = aspect_ratio_ultra * height;
-            let offset = (width - scaled_width) / 2.0;
-            (
-                bbox[0] * scaled_width + offset,
-                bbox[1] * height,
-                bbox[2] * scaled_width + offset,
-                bbox[3] * height,
-            )
-        } else if aspect_ratio_raw_image < aspect_ratio_ultra {
-            let scaled_height = (1.0 / aspect_ratio_ultra) * width;
-            let offset = (height - scaled_height) / 2.0;
-            (
-                bbox[0] * width,
-                bbox[1] * scaled_height + offset,
-                bbox[2] * width,
-                bbox[3] * scaled_height + offset,
-            )
-        } else {
-            // raw_image has same aspect ratio
-            (
-                bbox[0] * width,
-                bbox[1] * height,
-                bbox[2] * width,
-                bbox[3] * height,
-            )
-        };
+/// The raw image as RGB8, borrowed if it already is. Convert once per image and share the result
+/// between its faces, a conversion copies the whole image.
+pub fn as_rgb_image(raw_image: &DynamicImage) -> Cow<'_, RgbImage> {
+    match raw_image.as_rgb8() {
+        Some(rgb_image) => Cow::Borrowed(rgb_image),
+        None => Cow::Owned(raw_image.to_rgb8()),
+    }
+}
 
-    let rect_width = x_br - x_tl;
-    let rect_height = y_br - y_tl;
+// crop image returning sub_image, bbox is given in raw image pixel coordinates
+pub fn crop_raw_image(image: &DynamicImage, bbox: &Bbox) -> RgbImage {
+    let x_tl = bbox[0].max(0.0);
+    let y_tl = bbox[1].max(0.0);
+    let rect_width = bbox[2] - x_tl;
+    let rect_height = bbox[3] - y_tl;
 
-    let mut binding = image.to_rgb8();
-    let sub_image = crop_imm(
-        &mut binding,
+    let sub_image = image.crop_imm(
         x_tl as u32,
         y_tl as u32,
-        rect_width as u32,
-        rect_height as u32,
+        rect_width.max(1.0) as u32,
+        rect_height.max(1.0) as u32,
     );
-    Ok(sub_image.to_image())
+    sub_image.to_rgb8()
 }
@@ -1,93 +1,221 @@
-use std::{path::Path, process, time::Instant};
+use std::path::Path;
 
 use image::RgbImage;
 use ndarray::{Array4, CowArray, IxDyn};
-use ort::{
-    Environment, ExecutionProvider, GraphOptimizationLevel, LoggingLevel, OrtError, Session,
-    SessionBuilder, Value,
-};
+use ort::{OrtError, Session, Value};
+use rayon::prelude::*;
+use tracing::{instrument, warn};
 
 use crate::{
-    arcface_image::ArcFaceImage,
+    arcface_image::{as_rgb_image, ArcFaceImage},
+    error::PredictionError,
+    face_alignment::landmarks_from_bbox,
+    gallery::model_id,
+    landmark_predictor::LandmarkPredictor,
     post_processor::{ArcFaceOutput, UltraResult},
+    runtime::SessionOptions,
     ultra_image::UltraImage,
 };
 
 pub struct ArcFacePredictor {
     pub name: String,
     pub session: Session,
+    pub model_id: String,
+    pub landmark_predictor: Option<LandmarkPredictor>,
+    /// Maximum number of faces embedded in one session run, 1 for models with a fixed batch size
+    /// of 1.
+    pub max_batch_size: usize,
 }
 
 pub static ARC_FACE_NAME: &str = "ArcFacePredictor";
+pub static ARC_FACE_INPUT_SIZE: usize = 112;
+pub static DEFAULT_ARC_BATCH_SIZE: usize = 32;
 
 impl ArcFacePredictor {
-    pub fn new(model_filepath: &Path, num_threads: i16) -> Result<ArcFacePredictor, OrtError> {
-        let start = Instant::now();
-
-        let environment = Environment::builder()
-            .with_name(ARC_FACE_NAME.to_string())
-            .with_execution_providers([ExecutionProvider::CPU(Default::default())])
-            .with_log_level(LoggingLevel::Verbose)
-            .build()?
-            .into_arc();
-
-        let session = SessionBuilder::new(&environment)?
-            .with_optimization_level(GraphOptimizationLevel::Disable)?
-            .with_intra_threads(num_threads)?
-            .with_model_from_file(&model_filepath)?;
-
-        println!("{} startup took {:?}", ARC_FACE_NAME, start.elapsed());
+    #[instrument(level = "debug", skip(session_options))]
+    pub fn new(
+        model_filepath: &Path,
+        session_options: &SessionOptions,
+    ) -> Result<ArcFacePredictor, PredictionError> {
+        let session = session_options.build_session(model_filepath)?;
+        let model_id = model_id(model_filepath)?;
+
+        // NCHW input, a fixed batch dimension limits the batch size
+        let max_batch_size = session
+            .inputs
+            .first()
+            .and_then(|input| input.dimensions.first().copied().flatten())
+            .map_or(DEFAULT_ARC_BATCH_SIZE, |batch_size| batch_size as usize);
+
         Ok(ArcFacePredictor {
             name: ARC_FACE_NAME.to_string(),
             session,
+            model_id,
+            landmark_predictor: None,
+            max_batch_size,
         })
     }
 
+    /// Embed up to `max_batch_size` faces per session run. Models with a fixed batch dimension
+    /// keep their batch size.
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        if self.has_dynamic_batch() {
+            self.max_batch_size = max_batch_size.max(1);
+        } else if max_batch_size != self.max_batch_size {
+            warn!(
+                "{} has a fixed batch size of {}, ignoring batch size {}",
+                ARC_FACE_NAME, self.max_batch_size, max_batch_size
+            );
+        }
+        self
+    }
+
+    /// Locate the landmarks for alignment with an SCRFD model instead of the bounding box prior.
+    pub fn with_landmark_predictor(mut self, landmark_predictor: LandmarkPredictor) -> Self {
+        self.landmark_predictor = Some(landmark_predictor);
+        self
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(image = ?ultra_image.image_path, faces = bboxes.len())
+    )]
     pub fn run(
         &self,
         ultra_image: &UltraImage,
         bboxes: &UltraResult,
-    ) -> Result<Vec<ArcFaceOutput>, OrtError> {
-        let start = Instant::now();
-        let mut arc_face_outputs: Vec<ArcFaceOutput> = vec![];
+    ) -> Result<Vec<ArcFaceOutput>, PredictionError> {
+        let faces = self.align_faces(ultra_image, bboxes)?;
+        self.embed_faces(&faces)
+    }
+
+    /// Embed the faces of several images together, stacking the faces of all images into batches
+    /// of up to `max_batch_size`. Returns the outputs of every image in the order of `images`.
+    ///
+    /// An image whose faces can not be aligned fails on its own. If a batched run fails, the
+    /// images are embedded one by one so the error is attributed to the right image.
+    #[instrument(level = "debug", skip_all, fields(images = images.len()))]
+    pub fn run_batch(
+        &self,
+        images: &[(&UltraImage, &UltraResult)],
+    ) -> Vec<Result<Vec<ArcFaceOutput>, PredictionError>> {
+        let aligned: Vec<Result<Vec<RgbImage>, PredictionError>> = images
+            .par_iter()
+            .map(|(ultra_image, bboxes)| self.align_faces(ultra_image, bboxes))
+            .collect();
+
+        // images that failed to align embed no faces, so every image gets an output
+        let faces: Vec<&[RgbImage]> = aligned
+            .iter()
+            .map(|image_faces| image_faces.as_ref().map_or(&[][..], Vec::as_slice))
+            .collect();
+        let outputs = self.embed_images(&faces);
+        aligned
+            .into_iter()
+            .zip(outputs)
+            .map(|(image_faces, outputs)| {
+                image_faces?;
+                outputs
+            })
+            .collect()
+    }
+
+    /// Embed the aligned faces of several images together, stacking the faces of all images into
+    /// batches of up to `max_batch_size`. Returns the outputs of every image in the order of
+    /// `images`.
+    ///
+    /// If a batched run fails, the images are embedded one by one so the error is attributed to
+    /// the right image.
+    #[instrument(level = "debug", skip_all, fields(images = images.len()))]
+    pub fn embed_images(
+        &self,
+        images: &[&[RgbImage]],
+    ) -> Vec<Result<Vec<ArcFaceOutput>, PredictionError>> {
+        let faces: Vec<&RgbImage> = images.iter().flat_map(|faces| faces.iter()).collect();
+        let mut outputs = match self.embed_face_refs(&faces) {
+            Ok(outputs) => outputs.into_iter(),
+            Err(error) => {
+                warn!(
+                    "Batched embedding failed, embedding images one by one: {}",
+                    error
+                );
+                return images
+                    .iter()
+                    .map(|image_faces| self.embed_faces(image_faces))
+                    .collect();
+            }
+        };
+
+        images
+            .iter()
+            .map(|image_faces| Ok(outputs.by_ref().take(image_faces.len()).collect()))
+            .collect()
+    }
+
+    /// Align every face of `bboxes` to the ArcFace template with the landmarks of the landmark
+    /// model, or with `landmarks_from_bbox` if there is none or it misses the face.
+    pub fn align_faces(
+        &self,
+        ultra_image: &UltraImage,
+        bboxes: &UltraResult,
+    ) -> Result<Vec<RgbImage>, PredictionError> {
+        let rgb_image = as_rgb_image(&ultra_image.raw_image);
+        bboxes
+            .iter()
+            .map(|(bbox, _)| {
+                let landmarks = match &self.landmark_predictor {
+                    Some(landmark_predictor) => landmark_predictor
+                        .run(&ultra_image.raw_image, bbox)?
+                        .unwrap_or_else(|| landmarks_from_bbox(bbox)),
+                    None => landmarks_from_bbox(bbox),
+                };
+                let image = ArcFaceImage::new(&rgb_image, &landmarks)
+                    .map_err(PredictionError::image(ultra_image.image_path))?;
+                Ok(image.image)
+            })
+            .collect()
+    }
 
-        for (bbox, _) in bboxes {
-            let image = ArcFaceImage::new(ultra_image.raw_image.clone(), bbox.clone())
-                .expect("something went wrong");
-            let image_tensor = self.get_image_tensor(&image.image);
+    /// Embed aligned faces in batches of up to `max_batch_size`.
+    #[instrument(level = "debug", skip_all, fields(faces = faces.len()))]
+    pub fn embed_faces(&self, faces: &[RgbImage]) -> Result<Vec<ArcFaceOutput>, PredictionError> {
+        self.embed_face_refs(&faces.iter().collect::<Vec<&RgbImage>>())
+    }
+
+    fn embed_face_refs(&self, faces: &[&RgbImage]) -> Result<Vec<ArcFaceOutput>, PredictionError> {
+        let mut arc_face_outputs: Vec<ArcFaceOutput> = vec![];
+        for batch in faces.chunks(self.max_batch_size.max(1)) {
+            let image_tensor = self.get_image_tensor(batch);
             let image_input = self.get_image_input(&image_tensor)?;
-            let raw_outputs = self.session.run(image_input).unwrap_or_else(|err| {
-                println!("somehting went wrong running session: {}", err);
-                process::exit(1)
-            });
-            arc_face_outputs.push(ArcFaceOutput::new(raw_outputs)?);
+            let raw_outputs = self.session.run(image_input)?;
+            arc_face_outputs.extend(ArcFaceOutput::from_batch(raw_outputs, batch.len())?);
         }
-
-        println!(
-            "{} preprocessing and inference took {:?}",
-            ARC_FACE_NAME,
-            start.elapsed()
-        );
         Ok(arc_face_outputs)
     }
 
-    fn get_image_tensor(&self, image: &RgbImage) -> CowArray<f32, IxDyn> {
-        let image_tensor = CowArray::from(Array4::from_shape_fn(
-            (1, 3, 112 as usize, 112 as usize),
-            |(_, c, y, x)| ((image[(x as _, y as _)][c] as f32 / 255.0) - 0.5) / 0.5,
-        ))
-        .into_dyn();
+    fn has_dynamic_batch(&self) -> bool {
+        self.session
+            .inputs
+            .first()
+            .and_then(|input| input.dimensions.first().copied().flatten())
+            .is_none()
+    }
 
-        return image_tensor;
+    fn get_image_tensor(&self, images: &[&RgbImage]) -> CowArray<'_, f32, IxDyn> {
+        CowArray::from(Array4::from_shape_fn(
+            (images.len(), 3, ARC_FACE_INPUT_SIZE, ARC_FACE_INPUT_SIZE),
+            |(b, c, y, x)| ((images[b][(x as _, y as _)][c] as f32 / 255.0) - 0.5) / 0.5,
+        ))
+        .into_dyn()
     }
 
     fn get_image_input<'a>(
         &self,
         image_tensor: &'a CowArray<'a, f32, IxDyn>,
     ) -> Result<Vec<Value<'a>>, OrtError> {
-        let input_value = Value::from_array(self.session.allocator(), &image_tensor)?;
-        let input = vec![input_value];
+        let input_value = Value::from_array(self.session.allocator(), image_tensor)?;
 
-        return Ok(input);
+        Ok(vec![input_value])
     }
 }
@@ -0,0 +1,414 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use rayon::prelude::*;
+use serde::Serialize;
+use tracing::{instrument, warn};
+
+use crate::{
+    error::PredictionError,
+    face::ImageFaces,
+    face_crop::{crop_square, CropOptions},
+    metric::FaceMatcher,
+    normalize_embedding,
+    post_processor::Bbox,
+    vector_index::{AnyIndex, IndexKind, VectorIndex},
+};
+
+pub static CLUSTERS_FILE_NAME: &str = "clusters.json";
+pub static CLUSTER_CROPS_FOLDER_NAME: &str = "clusters";
+pub static DEFAULT_MIN_CLUSTER_SIZE: usize = 2;
+pub static DEFAULT_MAX_NEIGHBOURS: usize = 20;
+/// Chinese whispers usually settles within a handful of passes, this only bounds oscillations.
+static MAX_ITERATIONS: usize = 100;
+
+/// How faces are grouped by `cluster_faces`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClusterOptions {
+    /// Smallest group of faces reported as a cluster.
+    pub min_cluster_size: usize,
+    /// Closest matching faces linked to every face, bounds the size of the neighbour graph.
+    pub max_neighbours: usize,
+    /// Index used to find the neighbours of every face, hnsw avoids comparing all pairs of faces.
+    pub index: IndexKind,
+}
+
+impl Default for ClusterOptions {
+    fn default() -> Self {
+        ClusterOptions {
+            min_cluster_size: DEFAULT_MIN_CLUSTER_SIZE,
+            max_neighbours: DEFAULT_MAX_NEIGHBOURS,
+            index: IndexKind::Hnsw,
+        }
+    }
+}
+
+/// A face of the collection with the cluster it was assigned to.
+#[derive(Serialize, Clone, Debug)]
+pub struct ClusteredFace {
+    pub image_path: PathBuf,
+    /// Index of the face in the image, in order of detection confidence.
+    pub face_index: usize,
+    /// Bounding box in raw image pixel coordinates.
+    pub bbox: Bbox,
+    pub confidence: f32,
+    /// `None` if the face ended up in a cluster smaller than the minimum cluster size.
+    pub cluster_id: Option<usize>,
+}
+
+/// Faces assumed to belong to the same person.
+#[derive(Serialize, Clone, Debug)]
+pub struct Cluster {
+    pub id: usize,
+    pub size: usize,
+    /// Face closest to the mean embedding of the cluster, as an index into `Clustering::faces`.
+    pub representative: usize,
+    pub representative_image_path: PathBuf,
+    pub representative_face_index: usize,
+    /// Members of the cluster, as indices into `Clustering::faces`.
+    pub faces: Vec<usize>,
+}
+
+/// Faces of a collection grouped by person. Clusters are ordered by decreasing size and numbered
+/// from 0.
+#[derive(Serialize, Clone, Debug)]
+pub struct Clustering {
+    pub faces: Vec<ClusteredFace>,
+    pub clusters: Vec<Cluster>,
+}
+
+/// Group the faces of `images` by person with Chinese whispers.
+///
+/// Every face is linked both ways to its `max_neighbours` closest faces that `matcher` accepts and
+/// repeatedly takes the cluster its neighbours belong to, weighting each neighbour by closeness. Unlike a plain threshold this
+/// does not chain everyone into one cluster through a few ambiguous faces, and the number of
+/// people does not have to be known. Clusters with fewer than `min_cluster_size` faces are left
+/// unclustered.
+#[instrument(level = "debug", skip_all, fields(images = images.len()))]
+pub fn cluster_faces(
+    images: &[ImageFaces],
+    matcher: &FaceMatcher,
+    options: &ClusterOptions,
+) -> Clustering {
+    let mut faces: Vec<ClusteredFace> = vec![];
+    let mut embeddings: Vec<&[f32]> = vec![];
+    for image in images {
+        for (face_index, face) in image.faces.iter().enumerate() {
+            faces.push(ClusteredFace {
+                image_path: image.image_path.clone(),
+                face_index,
+                bbox: face.bbox,
+                confidence: face.confidence,
+                cluster_id: None,
+            });
+            embeddings.push(&face.embedding);
+        }
+    }
+
+    let neighbours = neighbour_graph(&embeddings, matcher, options);
+    let labels = chinese_whispers(&neighbours);
+
+    let mut members: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (face, label) in labels.into_iter().enumerate() {
+        members.entry(label).or_default().push(face);
+    }
+    let mut groups: Vec<Vec<usize>> = members
+        .into_values()
+        .filter(|group| group.len() >= options.min_cluster_size.max(1))
+        .collect();
+    // members are in face order, so ties are broken by the first face of the cluster
+    groups.sort_by(|a, b| b.len().cmp(&a.len()).then(a[0].cmp(&b[0])));
+
+    let clusters = groups
+        .into_iter()
+        .enumerate()
+        .map(|(id, group)| {
+            for &face in &group {
+                faces[face].cluster_id = Some(id);
+            }
+            let representative = representative(&group, &embeddings, matcher);
+            Cluster {
+                id,
+                size: group.len(),
+                representative,
+                representative_image_path: faces[representative].image_path.clone(),
+                representative_face_index: faces[representative].face_index,
+                faces: group,
+            }
+        })
+        .collect();
+
+    Clustering { faces, clusters }
+}
+
+/// Undirected graph linking every face to its up to `max_neighbours` closest matching faces,
+/// found with a vector index so the faces are not compared pairwise. Edges are weighted by
+/// closeness, the maximum distance of the metric minus the distance.
+fn neighbour_graph(
+    embeddings: &[&[f32]],
+    matcher: &FaceMatcher,
+    options: &ClusterOptions,
+) -> Vec<Vec<(usize, f32)>> {
+    let mut index = AnyIndex::new(options.index);
+    for (face, embedding) in embeddings.iter().enumerate() {
+        index.insert(face as u64, embedding.to_vec());
+    }
+    let max_distance = matcher.metric.max_distance();
+    let nearest: Vec<Vec<(usize, f32)>> = embeddings
+        .par_iter()
+        .enumerate()
+        .map(|(face, embedding)| {
+            // the face itself is usually its own closest neighbour
+            index
+                .search(embedding, options.max_neighbours + 1)
+                .into_iter()
+                .map(|neighbour| neighbour.id as usize)
+                .filter(|&other_face| other_face != face)
+                .map(|other_face| {
+                    let distance = matcher.distance(embedding, embeddings[other_face]);
+                    (other_face, distance)
+                })
+                .filter(|&(_, distance)| matcher.is_match(distance))
+                .map(|(other_face, distance)| (other_face, max_distance - distance))
+                .take(options.max_neighbours)
+                .collect()
+        })
+        .collect();
+    symmetrize(&nearest)
+}
+
+/// Add the reverse of every edge of `neighbours`, so a face is linked to the faces that count it
+/// among their nearest neighbours even if they are not among its own. Neighbours are sorted.
+fn symmetrize(neighbours: &[Vec<(usize, f32)>]) -> Vec<Vec<(usize, f32)>> {
+    let mut edges: Vec<BTreeMap<usize, f32>> = vec![BTreeMap::new(); neighbours.len()];
+    for (face, face_neighbours) in neighbours.iter().enumerate() {
+        for &(neighbour, weight) in face_neighbours {
+            edges[face].insert(neighbour, weight);
+            edges[neighbour].insert(face, weight);
+        }
+    }
+    edges
+        .into_iter()
+        .map(|face_edges| face_edges.into_iter().collect())
+        .collect()
+}
+
+/// Cluster label of every face of the weighted `neighbours` graph, labels are not contiguous.
+fn chinese_whispers(neighbours: &[Vec<(usize, f32)>]) -> Vec<usize> {
+    // faces are visited in a fixed order and ties go to the lowest label, so runs are repeatable
+    let mut labels: Vec<usize> = (0..neighbours.len()).collect();
+    for _ in 0..MAX_ITERATIONS {
+        let mut changed = false;
+        for face in 0..neighbours.len() {
+            let mut votes: HashMap<usize, f32> = HashMap::new();
+            for &(neighbour, weight) in &neighbours[face] {
+                *votes.entry(labels[neighbour]).or_default() += weight;
+            }
+            let best = votes
+                .into_iter()
+                .max_by(|(label_a, votes_a), (label_b, votes_b)| {
+                    votes_a.total_cmp(votes_b).then(label_b.cmp(label_a))
+                })
+                .map(|(label, _)| label);
+            if let Some(label) = best {
+                if label != labels[face] {
+                    labels[face] = label;
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    labels
+}
+
+fn representative(group: &[usize], embeddings: &[&[f32]], matcher: &FaceMatcher) -> usize {
+    let mut sum = vec![0.0; embeddings[group[0]].len()];
+    for &face in group {
+        for (total, value) in sum.iter_mut().zip(embeddings[face]) {
+            *total += value;
+        }
+    }
+    let centroid = normalize_embedding(sum);
+    group
+        .iter()
+        .copied()
+        .min_by(|&a, &b| {
+            matcher
+                .distance(&centroid, embeddings[a])
+                .total_cmp(&matcher.distance(&centroid, embeddings[b]))
+        })
+        .unwrap_or(group[0])
+}
+
+/// Write the faces of every cluster as square crops to `output_folder/cluster_{id}`. Crops are
+/// named after the position of the face in `clustering.faces` and its source image, so faces of
+/// images with the same name do not collide. Images that can not be read are skipped.
+///
+/// `output_folder` is emptied first so crops of an earlier clustering do not mix in.
+pub fn export_cluster_crops(
+    clustering: &Clustering,
+    output_folder: &Path,
+    options: &CropOptions,
+) -> Result<Vec<PathBuf>, PredictionError> {
+    if output_folder.exists() {
+        fs::remove_dir_all(output_folder)?;
+    }
+    fs::create_dir_all(output_folder)?;
+
+    let mut images: BTreeMap<&Path, Vec<usize>> = BTreeMap::new();
+    for (face, clustered_face) in clustering.faces.iter().enumerate() {
+        if clustered_face.cluster_id.is_some() {
+            images
+                .entry(clustered_face.image_path.as_path())
+                .or_default()
+                .push(face);
+        }
+    }
+
+    let mut crop_paths: Vec<PathBuf> = vec![];
+    for (image_path, image_faces) in images {
+        match export_image_crops(clustering, image_path, &image_faces, output_folder, options) {
+            Ok(image_crop_paths) => crop_paths.extend(image_crop_paths),
+            Err(error) => warn!("Skipping image: {}", error),
+        }
+    }
+    Ok(crop_paths)
+}
+
+fn export_image_crops(
+    clustering: &Clustering,
+    image_path: &Path,
+    image_faces: &[usize],
+    output_folder: &Path,
+    options: &CropOptions,
+) -> Result<Vec<PathBuf>, PredictionError> {
+    let raw_image = image::open(image_path).map_err(PredictionError::image(image_path))?;
+    let file_stem = image_path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+
+    let mut crop_paths: Vec<PathBuf> = vec![];
+    for &face in image_faces {
+        let clustered_face = &clustering.faces[face];
+        let cluster_folder = match clustered_face.cluster_id {
+            Some(cluster_id) => output_folder.join(format!("cluster_{}", cluster_id)),
+            None => continue,
+        };
+        fs::create_dir_all(&cluster_folder)?;
+        let crop_path = cluster_folder.join(format!(
+            "{}_{}_{}.{}",
+            face,
+            file_stem,
+            clustered_face.face_index,
+            options.format.extension()
+        ));
+        crop_square(
+            &raw_image,
+            &clustered_face.bbox,
+            options.size,
+            options.margin,
+        )
+        .save_with_format(&crop_path, options.format.image_format())
+        .map_err(PredictionError::image(&crop_path))?;
+        crop_paths.push(crop_path);
+    }
+    Ok(crop_paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{face::Face, metric::DistanceMetric};
+
+    /// Every pair of `faces` linked with weight 1.
+    fn clique(faces: std::ops::Range<usize>) -> Vec<(usize, usize, f32)> {
+        let mut edges = vec![];
+        for face in faces.clone() {
+            for other_face in face + 1..faces.end {
+                edges.push((face, other_face, 1.0));
+            }
+        }
+        edges
+    }
+
+    /// Undirected graph of `faces` faces from weighted edges.
+    fn graph(faces: usize, edges: &[(usize, usize, f32)]) -> Vec<Vec<(usize, f32)>> {
+        let mut neighbours = vec![vec![]; faces];
+        for &(face, other_face, weight) in edges {
+            neighbours[face].push((other_face, weight));
+        }
+        symmetrize(&neighbours)
+    }
+
+    #[test]
+    fn symmetrize_adds_reverse_edges_once() {
+        assert_eq!(
+            symmetrize(&[vec![(1, 0.5), (2, 1.0)], vec![(0, 0.5)], vec![]]),
+            vec![vec![(1, 0.5), (2, 1.0)], vec![(0, 0.5)], vec![(0, 1.0)]]
+        );
+    }
+
+    #[test]
+    fn chinese_whispers_separates_cliques_joined_by_a_weak_edge() {
+        let mut edges = clique(0..4);
+        edges.extend(clique(4..9));
+        edges.push((3, 4, 0.5));
+        let labels = chinese_whispers(&graph(10, &edges));
+
+        assert!(labels[..4].iter().all(|&label| label == labels[0]));
+        assert!(labels[4..9].iter().all(|&label| label == labels[4]));
+        assert_ne!(labels[0], labels[4]);
+        // a face without neighbours keeps its own label
+        assert_eq!(labels[9], 9);
+    }
+
+    #[test]
+    fn cluster_faces_groups_people_and_drops_small_clusters() {
+        let face = |embedding: [f32; 2]| Face {
+            bbox: [0.0, 0.0, 10.0, 10.0],
+            confidence: 0.9,
+            embedding: normalize_embedding(embedding.to_vec()),
+        };
+        let images = vec![
+            ImageFaces {
+                image_path: PathBuf::from("a.jpg"),
+                faces: vec![face([1.0, 0.0]), face([0.0, 1.0])],
+            },
+            ImageFaces {
+                image_path: PathBuf::from("b.jpg"),
+                faces: vec![face([0.1, 1.0]), face([1.0, 0.1]), face([1.0, 0.05])],
+            },
+            ImageFaces {
+                image_path: PathBuf::from("c.jpg"),
+                faces: vec![face([-1.0, 0.0])],
+            },
+        ];
+        let options = ClusterOptions {
+            index: IndexKind::Flat,
+            ..ClusterOptions::default()
+        };
+        let clustering =
+            cluster_faces(&images, &FaceMatcher::new(DistanceMetric::Cosine), &options);
+
+        let cluster_ids: Vec<Option<usize>> = clustering
+            .faces
+            .iter()
+            .map(|face| face.cluster_id)
+            .collect();
+        assert_eq!(
+            cluster_ids,
+            vec![Some(0), Some(1), Some(1), Some(0), Some(0), None]
+        );
+        assert_eq!(clustering.clusters.len(), 2);
+        assert_eq!(clustering.clusters[0].faces, vec![0, 3, 4]);
+        assert_eq!(clustering.clusters[1].size, 2);
+    }
+}
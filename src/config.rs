@@ -1,29 +1,857 @@
+use std::{error::Error, fs, path::Path};
+
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    clustering::{DEFAULT_MAX_NEIGHBOURS, DEFAULT_MIN_CLUSTER_SIZE},
+    face_crop::{CropFormat, CropOptions, DEFAULT_CROP_MARGIN, DEFAULT_CROP_SIZE},
+    logging::OrtLogLevel,
+    metric::{DistanceMetric, FaceMatcher},
+    output::OutputFormat,
+    pipeline::{
+        PipelineOptions, DEFAULT_ALIGN_WORKERS, DEFAULT_DECODE_WORKERS, DEFAULT_DETECT_WORKERS,
+        DEFAULT_EMBED_WORKERS,
+    },
+    post_processor::{DetectionOptions, DEFAULT_CONFIDENCE_THRESHOLD, DEFAULT_MAX_IOU},
+    predictor_pool::{threads_per_session, DEFAULT_SESSIONS},
+    runtime::{ExecutionMode, OptimizationLevel, SessionOptions},
+    ultra_image::ResizeMode,
+    ultra_predictor::DEFAULT_TILE_OVERLAP,
+    vector_index::IndexKind,
+    DEFAULT_CHUNK_SIZE,
+};
+
+pub static DEFAULT_ULTRA_MODEL_PATH: &str = "models/version-RFB-640.onnx";
+pub static DEFAULT_ARC_MODEL_PATH: &str = "models/arcfaceresnet100-11-int8.onnx";
+pub static DEFAULT_RESULT_FOLDER: &str = "output";
+pub static DEFAULT_SESSION_THREADS: i16 = 10;
+pub static DEFAULT_LOG_LEVEL: &str = "info";
+
+/// Settings resolved from the command line, the configuration file and the defaults, in that
+/// order of precedence.
+#[derive(Debug)]
 pub struct Config {
     pub ultra_model_path: String,
     pub arc_model_path: String,
-    pub folder_path: String,
+    pub landmark_model_path: Option<String>,
     pub result_folder: String,
-    pub test_case_path: String,
+    pub threads: i16,
+    /// Intra-op threads shared by all onnx sessions, `None` gives every session `threads`.
+    pub thread_budget: Option<i16>,
+    pub ultra_sessions: usize,
+    pub arc_sessions: usize,
+    pub optimization_level: OptimizationLevel,
+    /// Inter-op threads per onnx session, `None` keeps the ONNX Runtime default.
+    pub inter_threads: Option<i16>,
+    pub memory_arena: bool,
+    pub execution_mode: ExecutionMode,
+    /// Images buffered between two stages of the pipeline.
+    pub chunk_size: usize,
+    pub decode_workers: usize,
+    pub detect_workers: usize,
+    pub align_workers: usize,
+    pub embed_workers: usize,
+    /// Maximum number of images detected in one Ultraface run, `None` keeps the model default.
+    pub ultra_batch_size: Option<usize>,
+    /// Maximum number of faces embedded in one ArcFace run, `None` keeps the model default.
+    pub arc_batch_size: Option<usize>,
+    pub confidence_threshold: f32,
+    pub max_iou: f32,
+    pub ultra_input_width: Option<usize>,
+    pub ultra_input_height: Option<usize>,
+    pub resize_mode: ResizeMode,
+    pub tiled: bool,
+    pub tile_width: Option<u32>,
+    pub tile_height: Option<u32>,
+    pub tile_overlap: u32,
+    /// Metric faces are compared with.
+    pub metric: DistanceMetric,
+    /// Maximum distance in `metric` between the embeddings of faces of the same person.
+    pub match_distance: f32,
+    /// TrueType font used for the labels of annotated images.
+    pub annotation_font: Option<String>,
+    pub crop_size: u32,
+    pub crop_margin: f32,
+    pub crop_format: CropFormat,
+    pub aligned_crops: bool,
+    pub output_format: OutputFormat,
+    /// Save search and compare results to `result_folder` instead of printing them.
+    pub save_results: bool,
+    /// Log filter, `None` falls back to `RUST_LOG` and then to `DEFAULT_LOG_LEVEL`.
+    pub log_level: Option<String>,
+    pub ort_log_level: OrtLogLevel,
+    pub command: Command,
+}
+
+/// Face prediction CLI using Ultraface and ArcFace.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    /// TOML configuration file, command line flags override its values
+    #[arg(long, global = true)]
+    config: Option<String>,
+
+    /// Ultraface onnx model used for face detection [default: models/version-RFB-640.onnx]
+    #[arg(long, global = true)]
+    ultra_model_path: Option<String>,
+
+    /// ArcFace onnx model used for face embeddings [default: models/arcfaceresnet100-11-int8.onnx]
+    #[arg(long, global = true)]
+    arc_model_path: Option<String>,
+
+    /// SCRFD onnx model with keypoints locating the landmarks for face alignment
+    #[arg(long, global = true)]
+    landmark_model_path: Option<String>,
+
+    /// Folder the gallery and other results are stored in [default: output]
+    #[arg(long, global = true)]
+    result_folder: Option<String>,
+
+    /// Number of intra-op threads per onnx session [default: 10]
+    #[arg(long, global = true)]
+    threads: Option<i16>,
+
+    /// Total number of intra-op threads, split evenly over the Ultraface, ArcFace and landmark
+    /// sessions instead of giving each session --threads
+    #[arg(long, global = true)]
+    thread_budget: Option<i16>,
+
+    /// Number of Ultraface sessions running detection in parallel [default: 1]
+    #[arg(long, global = true)]
+    ultra_sessions: Option<usize>,
+
+    /// Number of ArcFace sessions running embedding in parallel [default: 1]
+    #[arg(long, global = true)]
+    arc_sessions: Option<usize>,
+
+    /// Graph optimizations applied when the models are loaded [default: disable]
+    #[arg(long, global = true, value_enum)]
+    optimization_level: Option<OptimizationLevel>,
+
+    /// Number of inter-op threads per onnx session, used in parallel execution mode [default:
+    /// ONNX Runtime default]
+    #[arg(long, global = true)]
+    inter_threads: Option<i16>,
+
+    /// Reuse allocations of the CPU memory arena between runs
+    #[arg(long, global = true)]
+    memory_arena: bool,
+
+    /// How the nodes of the model graphs are scheduled [default: sequential]
+    #[arg(long, global = true, value_enum)]
+    execution_mode: Option<ExecutionMode>,
+
+    /// Number of images buffered between two stages of the pipeline [default: 10]
+    #[arg(long, global = true)]
+    chunk_size: Option<usize>,
+
+    /// Number of threads decoding images [default: 4]
+    #[arg(long, global = true)]
+    decode_workers: Option<usize>,
+
+    /// Number of threads running Ultraface detection [default: 1]
+    #[arg(long, global = true)]
+    detect_workers: Option<usize>,
+
+    /// Number of threads aligning detected faces [default: 2]
+    #[arg(long, global = true)]
+    align_workers: Option<usize>,
+
+    /// Number of threads running ArcFace embedding [default: 1]
+    #[arg(long, global = true)]
+    embed_workers: Option<usize>,
+
+    /// Maximum number of images detected in one Ultraface run, only for models with a dynamic
+    /// batch size [default: 8]
+    #[arg(long, global = true)]
+    ultra_batch_size: Option<usize>,
+
+    /// Maximum number of faces embedded in one ArcFace run, only for models with a dynamic batch
+    /// size [default: 32]
+    #[arg(long, global = true)]
+    arc_batch_size: Option<usize>,
+
+    /// Minimum confidence of a detected face [default: 0.7]
+    #[arg(long, global = true)]
+    confidence_threshold: Option<f32>,
+
+    /// Maximum IoU between detected faces before the less confident one is suppressed [default: 0.5]
+    #[arg(long, global = true)]
+    max_iou: Option<f32>,
+
+    /// Ultraface input width for models with a dynamic input size [default: read from the model]
+    #[arg(long, global = true)]
+    ultra_input_width: Option<usize>,
+
+    /// Ultraface input height for models with a dynamic input size [default: read from the model]
+    #[arg(long, global = true)]
+    ultra_input_height: Option<usize>,
+
+    /// How images are fitted to the Ultraface input [default: letterbox]
+    #[arg(long, global = true, value_enum)]
+    resize_mode: Option<ResizeMode>,
+
+    /// Also detect faces on overlapping tiles at native resolution, for small faces in large images
+    #[arg(long, global = true)]
+    tiled: bool,
+
+    /// Tile width in raw image pixels [default: Ultraface input width]
+    #[arg(long, global = true)]
+    tile_width: Option<u32>,
+
+    /// Tile height in raw image pixels [default: Ultraface input height]
+    #[arg(long, global = true)]
+    tile_height: Option<u32>,
+
+    /// Overlap of neighbouring tiles in pixels [default: 128]
+    #[arg(long, global = true)]
+    tile_overlap: Option<u32>,
+
+    /// Metric faces are compared with, lower is closer for all of them [default: squared-euclidean]
+    #[arg(long, global = true, value_enum)]
+    metric: Option<DistanceMetric>,
+
+    /// Maximum distance in the metric between the embeddings of faces of the same person
+    /// [default: 0.6 cosine, 1.095 euclidean, 1.2 squared-euclidean]
+    #[arg(long, global = true)]
+    match_distance: Option<f32>,
+
+    /// TrueType font for the labels of annotated images, only boxes are drawn without it
+    #[arg(long, global = true)]
+    annotation_font: Option<String>,
+
+    /// Width and height of exported face crops in pixels [default: 112]
+    #[arg(long, global = true)]
+    crop_size: Option<u32>,
+
+    /// Context kept around exported face crops as a fraction of the face size [default: 0.2]
+    #[arg(long, global = true)]
+    crop_margin: Option<f32>,
+
+    /// Image format of exported face crops [default: png]
+    #[arg(long, global = true, value_enum)]
+    crop_format: Option<CropFormat>,
+
+    /// Align exported face crops to the ArcFace template instead of cropping the bounding box
+    #[arg(long, global = true)]
+    aligned_crops: bool,
+
+    /// Format of search and compare results [default: text]
+    #[arg(long, global = true, value_enum)]
+    output_format: Option<OutputFormat>,
+
+    /// Save search and compare results to the result folder instead of printing them
+    #[arg(long, global = true)]
+    save_results: bool,
+
+    /// Log filter, e.g. debug or face_prediction=trace,ort=warn [default: RUST_LOG or info]
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+
+    /// Minimum severity of ONNX Runtime log messages [default: warning]
+    #[arg(long, global = true, value_enum)]
+    ort_log_level: Option<OrtLogLevel>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// Contents of the TOML configuration file, every value is optional.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub models: ModelsConfig,
+    pub detection: DetectionConfig,
+    pub runtime: RuntimeConfig,
+    pub output: OutputConfig,
+    pub matching: MatchingConfig,
+    pub crop: CropConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelsConfig {
+    pub ultra: Option<String>,
+    pub arc: Option<String>,
+    pub landmark: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DetectionConfig {
+    pub confidence_threshold: Option<f32>,
+    pub max_iou: Option<f32>,
+    pub input_width: Option<usize>,
+    pub input_height: Option<usize>,
+    pub resize_mode: Option<ResizeMode>,
+    pub tiled: Option<bool>,
+    pub tile_width: Option<u32>,
+    pub tile_height: Option<u32>,
+    pub tile_overlap: Option<u32>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeConfig {
+    pub threads: Option<i16>,
+    pub thread_budget: Option<i16>,
+    pub ultra_sessions: Option<usize>,
+    pub arc_sessions: Option<usize>,
+    pub optimization_level: Option<OptimizationLevel>,
+    pub inter_threads: Option<i16>,
+    pub memory_arena: Option<bool>,
+    pub execution_mode: Option<ExecutionMode>,
+    pub chunk_size: Option<usize>,
+    pub decode_workers: Option<usize>,
+    pub detect_workers: Option<usize>,
+    pub align_workers: Option<usize>,
+    pub embed_workers: Option<usize>,
+    pub ultra_batch_size: Option<usize>,
+    pub arc_batch_size: Option<usize>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    pub result_folder: Option<String>,
+    pub format: Option<OutputFormat>,
+    pub save_results: Option<bool>,
+    pub annotation_font: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchingConfig {
+    pub metric: Option<DistanceMetric>,
+    pub max_distance: Option<f32>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CropConfig {
+    pub size: Option<u32>,
+    pub margin: Option<f32>,
+    pub format: Option<CropFormat>,
+    pub aligned: Option<bool>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: Option<String>,
+    pub ort_level: Option<OrtLogLevel>,
+}
+
+impl FileConfig {
+    pub fn load(path: &Path) -> Result<FileConfig, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Detect faces in an image or every image of a folder
+    Detect {
+        /// Image or folder of images
+        path: String,
+    },
+    /// Print the normalized embedding of every face in an image
+    Embed {
+        /// Image to embed
+        image_path: String,
+    },
+    /// Index the faces of a folder into the gallery of the result folder
+    Index {
+        /// Folder of images to index
+        folder_path: String,
+    },
+    /// Draw the faces of an image or folder labelled with their best match in the gallery into
+    /// the result folder
+    Annotate {
+        /// Image or folder of images
+        path: String,
+    },
+    /// Export the faces of an image or folder as crops with a manifest into the result folder
+    Crop {
+        /// Image or folder of images
+        path: String,
+    },
+    /// Enroll reference images of a named identity into the gallery of the result folder
+    Enroll {
+        /// Name of the identity
+        name: String,
+
+        /// Reference images of the identity
+        #[arg(required = true)]
+        image_paths: Vec<String>,
+
+        /// Face of the reference images to enroll, in order of detection confidence
+        /// [default: the largest face]
+        #[arg(long)]
+        face_index: Option<usize>,
+
+        /// Replace the reference images of an existing identity instead of adding to them
+        #[arg(long)]
+        replace: bool,
+    },
+    /// Label every face of the gallery with the enrolled identity it matches, or unknown
+    Identify {
+        /// Index this folder before identifying
+        #[arg(long)]
+        folder_path: Option<String>,
+    },
+    /// Group the faces of the gallery by person
+    Cluster {
+        /// Index this folder before clustering
+        #[arg(long)]
+        folder_path: Option<String>,
+
+        /// Smallest group of faces reported as a cluster, faces of smaller groups stay unclustered
+        #[arg(long, default_value_t = DEFAULT_MIN_CLUSTER_SIZE)]
+        min_cluster_size: usize,
+
+        /// Closest matching faces linked to every face in the neighbour graph
+        #[arg(long, default_value_t = DEFAULT_MAX_NEIGHBOURS)]
+        max_neighbours: usize,
+
+        /// Index used to find the neighbours of every face, flat compares every pair of faces
+        /// [default: hnsw]
+        #[arg(long, value_enum)]
+        vector_index: Option<IndexKind>,
+
+        /// Also write the faces of every cluster as crops into the result folder
+        #[arg(long)]
+        export_crops: bool,
+    },
+    /// Rank the indexed images by distance to the face in a test image
+    Search {
+        /// Image with the face to search for
+        test_case_path: String,
+
+        /// Index this folder before searching
+        #[arg(long)]
+        folder_path: Option<String>,
+
+        /// Face of the test case image to search for, in order of detection confidence
+        /// [default: the largest face]
+        #[arg(long)]
+        face_index: Option<usize>,
+
+        /// Search a persistent vector index kept next to the gallery instead of scanning every
+        /// face, hnsw is approximate but fast on large galleries
+        #[arg(long, value_enum)]
+        vector_index: Option<IndexKind>,
+
+        /// Number of images to return [default: all, 10 with --vector-index]
+        #[arg(long)]
+        top_k: Option<usize>,
+    },
+    /// Distance between the face in one image and the faces in another
+    Compare {
+        /// Image with the face to compare
+        image_path: String,
+
+        /// Image to compare against
+        other_image_path: String,
+
+        /// Face of the first image to compare, in order of detection confidence
+        /// [default: the largest face]
+        #[arg(long)]
+        face_index: Option<usize>,
+    },
+    /// Decide whether the faces in two images belong to the same person
+    Verify {
+        /// First image
+        image_path: String,
+
+        /// Second image
+        other_image_path: String,
+
+        /// Face of the first image, in order of detection confidence [default: the largest face]
+        #[arg(long)]
+        face_index: Option<usize>,
+
+        /// Face of the second image, in order of detection confidence
+        /// [default: the face closest to the face of the first image]
+        #[arg(long)]
+        other_face_index: Option<usize>,
+    },
 }
 
 impl Config {
-    pub fn new(args: &[String]) -> Result<Config, &str> {
-        if args.len() < 6 {
-            return Err("Not enough arguments");
+    pub fn new(args: &[String]) -> Result<Config, clap::Error> {
+        let cli = Cli::try_parse_from(args)?;
+
+        let file_config = match &cli.config {
+            Some(config_path) => FileConfig::load(Path::new(config_path)).map_err(|err| {
+                Cli::command().error(
+                    ErrorKind::ValueValidation,
+                    format!("unable to read config file {:?}: {}", config_path, err),
+                )
+            })?,
+            None => FileConfig::default(),
+        };
+
+        let config = Config::merge(cli, file_config);
+        config
+            .validate()
+            .map_err(|message| Cli::command().error(ErrorKind::ValueValidation, message))?;
+
+        Ok(config)
+    }
+
+    pub fn detection_options(&self) -> DetectionOptions {
+        DetectionOptions {
+            confidence_threshold: self.confidence_threshold,
+            max_iou: self.max_iou,
         }
+    }
+
+    pub fn crop_options(&self) -> CropOptions {
+        CropOptions {
+            size: self.crop_size,
+            margin: self.crop_margin,
+            format: self.crop_format,
+            aligned: self.aligned_crops,
+        }
+    }
+
+    pub fn face_matcher(&self) -> FaceMatcher {
+        FaceMatcher::new(self.metric).with_threshold(self.match_distance)
+    }
+
+    /// Onnx sessions opened for a pipeline, every ArcFace predictor opens its own landmark
+    /// session when a landmark model is set.
+    pub fn session_count(&self) -> usize {
+        let landmark_sessions = match self.landmark_model_path {
+            Some(_) => self.arc_sessions,
+            None => 0,
+        };
+        self.ultra_sessions + self.arc_sessions + landmark_sessions
+    }
+
+    /// Intra-op threads of every onnx session.
+    pub fn session_threads(&self) -> i16 {
+        match self.thread_budget {
+            Some(thread_budget) => threads_per_session(thread_budget, self.session_count()),
+            None => self.threads,
+        }
+    }
+
+    pub fn session_options(&self) -> SessionOptions {
+        SessionOptions {
+            optimization_level: self.optimization_level,
+            intra_threads: self.session_threads(),
+            inter_threads: self.inter_threads,
+            memory_arena: self.memory_arena,
+            execution_mode: self.execution_mode,
+            log_level: self.ort_log_level,
+        }
+    }
 
-        let ultra_model_path = args[1].clone();
-        let arc_model_path = args[2].clone();
-        let folder_path = args[3].clone();
-        let result_folder = args[4].clone();
-        let test_case_path = args[5].clone();
+    pub fn pipeline_options(&self) -> PipelineOptions {
+        PipelineOptions {
+            decode_workers: self.decode_workers,
+            detect_workers: self.detect_workers,
+            align_workers: self.align_workers,
+            embed_workers: self.embed_workers,
+            queue_size: self.chunk_size,
+        }
+    }
+
+    fn merge(cli: Cli, file_config: FileConfig) -> Config {
+        // the default threshold depends on the metric it is measured in
+        let metric = cli
+            .metric
+            .or(file_config.matching.metric)
+            .unwrap_or_default();
+
+        Config {
+            ultra_model_path: cli
+                .ultra_model_path
+                .or(file_config.models.ultra)
+                .unwrap_or_else(|| DEFAULT_ULTRA_MODEL_PATH.to_string()),
+            arc_model_path: cli
+                .arc_model_path
+                .or(file_config.models.arc)
+                .unwrap_or_else(|| DEFAULT_ARC_MODEL_PATH.to_string()),
+            landmark_model_path: cli.landmark_model_path.or(file_config.models.landmark),
+            result_folder: cli
+                .result_folder
+                .or(file_config.output.result_folder)
+                .unwrap_or_else(|| DEFAULT_RESULT_FOLDER.to_string()),
+            threads: cli
+                .threads
+                .or(file_config.runtime.threads)
+                .unwrap_or(DEFAULT_SESSION_THREADS),
+            thread_budget: cli.thread_budget.or(file_config.runtime.thread_budget),
+            ultra_sessions: cli
+                .ultra_sessions
+                .or(file_config.runtime.ultra_sessions)
+                .unwrap_or(DEFAULT_SESSIONS),
+            arc_sessions: cli
+                .arc_sessions
+                .or(file_config.runtime.arc_sessions)
+                .unwrap_or(DEFAULT_SESSIONS),
+            optimization_level: cli
+                .optimization_level
+                .or(file_config.runtime.optimization_level)
+                .unwrap_or_default(),
+            inter_threads: cli.inter_threads.or(file_config.runtime.inter_threads),
+            memory_arena: cli.memory_arena || file_config.runtime.memory_arena.unwrap_or(false),
+            execution_mode: cli
+                .execution_mode
+                .or(file_config.runtime.execution_mode)
+                .unwrap_or_default(),
+            chunk_size: cli
+                .chunk_size
+                .or(file_config.runtime.chunk_size)
+                .unwrap_or(DEFAULT_CHUNK_SIZE),
+            decode_workers: cli
+                .decode_workers
+                .or(file_config.runtime.decode_workers)
+                .unwrap_or(DEFAULT_DECODE_WORKERS),
+            detect_workers: cli
+                .detect_workers
+                .or(file_config.runtime.detect_workers)
+                .unwrap_or(DEFAULT_DETECT_WORKERS),
+            align_workers: cli
+                .align_workers
+                .or(file_config.runtime.align_workers)
+                .unwrap_or(DEFAULT_ALIGN_WORKERS),
+            embed_workers: cli
+                .embed_workers
+                .or(file_config.runtime.embed_workers)
+                .unwrap_or(DEFAULT_EMBED_WORKERS),
+            ultra_batch_size: cli
+                .ultra_batch_size
+                .or(file_config.runtime.ultra_batch_size),
+            arc_batch_size: cli.arc_batch_size.or(file_config.runtime.arc_batch_size),
+            confidence_threshold: cli
+                .confidence_threshold
+                .or(file_config.detection.confidence_threshold)
+                .unwrap_or(DEFAULT_CONFIDENCE_THRESHOLD),
+            max_iou: cli
+                .max_iou
+                .or(file_config.detection.max_iou)
+                .unwrap_or(DEFAULT_MAX_IOU),
+            ultra_input_width: cli.ultra_input_width.or(file_config.detection.input_width),
+            ultra_input_height: cli
+                .ultra_input_height
+                .or(file_config.detection.input_height),
+            resize_mode: cli
+                .resize_mode
+                .or(file_config.detection.resize_mode)
+                .unwrap_or_default(),
+            tiled: cli.tiled || file_config.detection.tiled.unwrap_or(false),
+            tile_width: cli.tile_width.or(file_config.detection.tile_width),
+            tile_height: cli.tile_height.or(file_config.detection.tile_height),
+            tile_overlap: cli
+                .tile_overlap
+                .or(file_config.detection.tile_overlap)
+                .unwrap_or(DEFAULT_TILE_OVERLAP),
+            metric,
+            match_distance: cli
+                .match_distance
+                .or(file_config.matching.max_distance)
+                .unwrap_or_else(|| metric.default_threshold()),
+            annotation_font: cli.annotation_font.or(file_config.output.annotation_font),
+            crop_size: cli
+                .crop_size
+                .or(file_config.crop.size)
+                .unwrap_or(DEFAULT_CROP_SIZE),
+            crop_margin: cli
+                .crop_margin
+                .or(file_config.crop.margin)
+                .unwrap_or(DEFAULT_CROP_MARGIN),
+            crop_format: cli
+                .crop_format
+                .or(file_config.crop.format)
+                .unwrap_or_default(),
+            aligned_crops: cli.aligned_crops || file_config.crop.aligned.unwrap_or(false),
+            output_format: cli
+                .output_format
+                .or(file_config.output.format)
+                .unwrap_or_default(),
+            save_results: cli.save_results || file_config.output.save_results.unwrap_or(false),
+            log_level: cli.log_level.or(file_config.logging.level),
+            ort_log_level: cli
+                .ort_log_level
+                .or(file_config.logging.ort_level)
+                .unwrap_or_default(),
+            command: cli.command,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        require_file(&self.ultra_model_path, "ultra model")?;
+        require_file(&self.arc_model_path, "arc model")?;
+        if let Some(landmark_model_path) = &self.landmark_model_path {
+            require_file(landmark_model_path, "landmark model")?;
+        }
+        if self.threads < 1 {
+            return Err(format!("threads must be at least 1, got {}", self.threads));
+        }
+        if let Some(thread_budget) = self.thread_budget.filter(|budget| *budget < 1) {
+            return Err(format!(
+                "thread_budget must be at least 1, got {}",
+                thread_budget
+            ));
+        }
+        if let Some(inter_threads) = self.inter_threads.filter(|threads| *threads < 1) {
+            return Err(format!(
+                "inter_threads must be at least 1, got {}",
+                inter_threads
+            ));
+        }
+        if self.chunk_size < 1 {
+            return Err(format!(
+                "chunk_size must be at least 1, got {}",
+                self.chunk_size
+            ));
+        }
+        for (count, name) in [
+            (self.ultra_sessions, "ultra_sessions"),
+            (self.arc_sessions, "arc_sessions"),
+            (self.decode_workers, "decode_workers"),
+            (self.detect_workers, "detect_workers"),
+            (self.align_workers, "align_workers"),
+            (self.embed_workers, "embed_workers"),
+        ] {
+            if count < 1 {
+                return Err(format!("{} must be at least 1, got {}", name, count));
+            }
+        }
+        if self.ultra_batch_size == Some(0) {
+            return Err("ultra_batch_size must be at least 1, got 0".to_string());
+        }
+        if self.arc_batch_size == Some(0) {
+            return Err("arc_batch_size must be at least 1, got 0".to_string());
+        }
+        require_unit_interval(self.confidence_threshold, "confidence_threshold")?;
+        require_unit_interval(self.max_iou, "max_iou")?;
+        for tile_size in [self.tile_width, self.tile_height].into_iter().flatten() {
+            if tile_size <= self.tile_overlap {
+                return Err(format!(
+                    "tile size {} must be larger than the tile overlap {}",
+                    tile_size, self.tile_overlap
+                ));
+            }
+        }
+        match (self.ultra_input_width, self.ultra_input_height) {
+            (Some(width), Some(height)) if width > 0 && height > 0 => (),
+            (None, None) => (),
+            _ => {
+                return Err("ultra input width and height must both be set and positive".to_string())
+            }
+        }
+
+        if !(0.0..=self.metric.max_distance()).contains(&self.match_distance) {
+            return Err(format!(
+                "match_distance must be between 0 and {} for the {:?} metric, got {}",
+                self.metric.max_distance(),
+                self.metric,
+                self.match_distance
+            ));
+        }
+        if self.crop_size < 1 {
+            return Err(format!(
+                "crop_size must be at least 1, got {}",
+                self.crop_size
+            ));
+        }
+        if !(0.0..=2.0).contains(&self.crop_margin) {
+            return Err(format!(
+                "crop_margin must be between 0 and 2, got {}",
+                self.crop_margin
+            ));
+        }
+        if let Some(annotation_font) = &self.annotation_font {
+            require_file(annotation_font, "annotation font")?;
+        }
+        if let Some(log_level) = &self.log_level {
+            EnvFilter::try_new(log_level)
+                .map_err(|err| format!("invalid log level {:?}: {}", log_level, err))?;
+        }
+
+        match &self.command {
+            Command::Detect { path } | Command::Annotate { path } | Command::Crop { path } => {
+                if !Path::new(path).exists() {
+                    return Err(format!("image or folder {:?} does not exist", path));
+                }
+            }
+            Command::Embed { image_path } => require_file(image_path, "image")?,
+            Command::Index { folder_path } => require_folder(folder_path, "image folder")?,
+            Command::Enroll {
+                name, image_paths, ..
+            } => {
+                if name.trim().is_empty() {
+                    return Err("identity name must not be empty".to_string());
+                }
+                for image_path in image_paths {
+                    require_file(image_path, "reference image")?;
+                }
+            }
+            Command::Identify {
+                folder_path: Some(folder_path),
+            } => require_folder(folder_path, "image folder")?,
+            Command::Identify { folder_path: None } => (),
+            Command::Cluster {
+                folder_path: Some(folder_path),
+                ..
+            } => require_folder(folder_path, "image folder")?,
+            Command::Cluster {
+                folder_path: None, ..
+            } => (),
+            Command::Search {
+                test_case_path,
+                folder_path,
+                ..
+            } => {
+                require_file(test_case_path, "test case image")?;
+                if let Some(folder_path) = folder_path {
+                    require_folder(folder_path, "image folder")?;
+                }
+            }
+            Command::Compare {
+                image_path,
+                other_image_path,
+                ..
+            }
+            | Command::Verify {
+                image_path,
+                other_image_path,
+                ..
+            } => {
+                require_file(image_path, "image")?;
+                require_file(other_image_path, "image")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn require_file(path: &str, description: &str) -> Result<(), String> {
+    if !Path::new(path).is_file() {
+        return Err(format!("{} {:?} is not a file", description, path));
+    }
+    Ok(())
+}
+
+fn require_unit_interval(value: f32, name: &str) -> Result<(), String> {
+    if !(0.0..=1.0).contains(&value) {
+        return Err(format!("{} must be between 0 and 1, got {}", name, value));
+    }
+    Ok(())
+}
 
-        Ok(Config {
-            ultra_model_path,
-            arc_model_path,
-            folder_path,
-            result_folder,
-            test_case_path,
-        })
+fn require_folder(path: &str, description: &str) -> Result<(), String> {
+    if !Path::new(path).is_dir() {
+        return Err(format!("{} {:?} is not a folder", description, path));
     }
+    Ok(())
 }
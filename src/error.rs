@@ -0,0 +1,144 @@
+use std::{
+    error::Error,
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+use image::ImageError;
+use ort::OrtError;
+
+use crate::gallery::GalleryError;
+
+/// Error returned by the predictors and the processing functions of the library.
+#[derive(Debug)]
+pub enum PredictionError {
+    /// Creating the onnx session of a model failed.
+    ModelLoad {
+        model_path: PathBuf,
+        source: OrtError,
+    },
+    /// An image could not be opened, decoded or aligned.
+    Image {
+        image_path: PathBuf,
+        source: ImageError,
+    },
+    /// Running a model session failed.
+    Inference(OrtError),
+    /// A model returned outputs of an unexpected shape, usually because it is not the expected
+    /// kind of model.
+    OutputShape(String),
+    /// The image has no face, or not the requested one.
+    NoFace {
+        image_path: PathBuf,
+        face_index: Option<usize>,
+        face_count: usize,
+    },
+    /// The annotation font is not a valid TrueType font.
+    InvalidFont(PathBuf),
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// The face index file could not be encoded or decoded.
+    Index(bincode::Error),
+    Gallery(GalleryError),
+}
+
+impl PredictionError {
+    /// Wrap an error of loading the model at `model_path`, for use with `map_err`.
+    pub fn model_load(model_path: &Path) -> impl Fn(OrtError) -> PredictionError + '_ {
+        move |source| PredictionError::ModelLoad {
+            model_path: model_path.to_path_buf(),
+            source,
+        }
+    }
+
+    /// Wrap an error of decoding the image at `image_path`, for use with `map_err`.
+    pub fn image(image_path: &Path) -> impl Fn(ImageError) -> PredictionError + '_ {
+        move |source| PredictionError::Image {
+            image_path: image_path.to_path_buf(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for PredictionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PredictionError::ModelLoad { model_path, source } => {
+                write!(f, "unable to load model {:?}: {}", model_path, source)
+            }
+            PredictionError::Image { image_path, source } => {
+                write!(f, "unable to process image {:?}: {}", image_path, source)
+            }
+            PredictionError::Inference(source) => write!(f, "inference failed: {}", source),
+            PredictionError::OutputShape(message) => {
+                write!(f, "unexpected model output: {}", message)
+            }
+            PredictionError::NoFace {
+                image_path,
+                face_index: Some(face_index),
+                face_count,
+            } => write!(
+                f,
+                "no face {} in {:?}, found {} faces",
+                face_index, image_path, face_count
+            ),
+            PredictionError::NoFace { image_path, .. } => {
+                write!(f, "no faces found in {:?}", image_path)
+            }
+            PredictionError::InvalidFont(font_path) => {
+                write!(f, "{:?} is not a valid TrueType font", font_path)
+            }
+            PredictionError::Io(source) => write!(f, "{}", source),
+            PredictionError::Json(source) => write!(f, "{}", source),
+            PredictionError::Index(source) => write!(f, "invalid face index: {}", source),
+            PredictionError::Gallery(source) => write!(f, "{}", source),
+        }
+    }
+}
+
+impl Error for PredictionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PredictionError::ModelLoad { source, .. } => Some(source),
+            PredictionError::Image { source, .. } => Some(source),
+            PredictionError::Inference(source) => Some(source),
+            PredictionError::Io(source) => Some(source),
+            PredictionError::Json(source) => Some(source),
+            PredictionError::Index(source) => Some(source),
+            PredictionError::Gallery(source) => Some(source),
+            PredictionError::OutputShape(_)
+            | PredictionError::NoFace { .. }
+            | PredictionError::InvalidFont(_) => None,
+        }
+    }
+}
+
+impl From<OrtError> for PredictionError {
+    fn from(error: OrtError) -> Self {
+        PredictionError::Inference(error)
+    }
+}
+
+impl From<io::Error> for PredictionError {
+    fn from(error: io::Error) -> Self {
+        PredictionError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for PredictionError {
+    fn from(error: serde_json::Error) -> Self {
+        PredictionError::Json(error)
+    }
+}
+
+impl From<bincode::Error> for PredictionError {
+    fn from(error: bincode::Error) -> Self {
+        PredictionError::Index(error)
+    }
+}
+
+impl From<GalleryError> for PredictionError {
+    fn from(error: GalleryError) -> Self {
+        PredictionError::Gallery(error)
+    }
+}
@@ -0,0 +1,56 @@
+use std::path::PathBuf;
+
+use crate::{error::PredictionError, post_processor::Bbox};
+
+/// A detected face with its normalized ArcFace embedding.
+#[derive(Clone, Debug)]
+pub struct Face {
+    /// Bounding box in raw image pixel coordinates.
+    pub bbox: Bbox,
+    pub confidence: f32,
+    pub embedding: Vec<f32>,
+}
+
+impl Face {
+    pub fn area(&self) -> f32 {
+        (self.bbox[2] - self.bbox[0]).max(0.0) * (self.bbox[3] - self.bbox[1]).max(0.0)
+    }
+}
+
+/// Every face found in an image, in order of decreasing detection confidence. An image without
+/// faces has an empty list.
+#[derive(Clone, Debug)]
+pub struct ImageFaces {
+    pub image_path: PathBuf,
+    pub faces: Vec<Face>,
+}
+
+/// Which face of an image with several faces to use, e.g. for a probe image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaceSelection {
+    Largest,
+    MostConfident,
+    Index(usize),
+}
+
+impl ImageFaces {
+    /// The selected face and its index, `PredictionError::NoFace` if the image has no such face.
+    pub fn select(&self, selection: FaceSelection) -> Result<(usize, &Face), PredictionError> {
+        let mut faces = self.faces.iter().enumerate();
+        let face = match selection {
+            FaceSelection::Largest => faces.max_by(|(_, a), (_, b)| a.area().total_cmp(&b.area())),
+            FaceSelection::MostConfident => {
+                faces.max_by(|(_, a), (_, b)| a.confidence.total_cmp(&b.confidence))
+            }
+            FaceSelection::Index(index) => faces.nth(index),
+        };
+        face.ok_or_else(|| PredictionError::NoFace {
+            image_path: self.image_path.clone(),
+            face_index: match selection {
+                FaceSelection::Index(index) => Some(index),
+                _ => None,
+            },
+            face_count: self.faces.len(),
+        })
+    }
+}
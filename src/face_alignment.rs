@@ -0,0 +1,96 @@
+use image::{Rgb, RgbImage};
+use imageproc::geometric_transformations::{warp_into, Interpolation, Projection};
+
+use crate::post_processor::Bbox;
+
+/// Five facial landmarks in pixel coordinates, in the order left eye, right eye, nose tip,
+/// left mouth corner and right mouth corner.
+pub type Landmarks = [[f32; 2]; 5];
+
+/// Canonical landmark positions of the 112x112 ArcFace input the embedding models are trained on.
+pub static ARC_FACE_TEMPLATE: Landmarks = [
+    [38.2946, 51.6963],
+    [73.5318, 51.5014],
+    [56.0252, 71.7366],
+    [41.5493, 92.3655],
+    [70.7299, 92.2041],
+];
+
+/// Average landmark positions relative to an Ultraface bounding box, used when no landmarks are
+/// located. Warping these onto the template amounts to cropping and resizing the bounding box,
+/// so unlike real landmarks they do not correct head pose or detector jitter.
+static BBOX_LANDMARK_PRIOR: Landmarks = [
+    [0.31, 0.40],
+    [0.69, 0.40],
+    [0.50, 0.58],
+    [0.35, 0.76],
+    [0.65, 0.76],
+];
+
+/// `BBOX_LANDMARK_PRIOR` placed on a bounding box given in pixel coordinates.
+pub fn landmarks_from_bbox(bbox: &Bbox) -> Landmarks {
+    let (width, height) = (bbox[2] - bbox[0], bbox[3] - bbox[1]);
+    BBOX_LANDMARK_PRIOR.map(|[x, y]| [bbox[0] + x * width, bbox[1] + y * height])
+}
+
+/// Warp `image` so that `landmarks` end up on the ArcFace template, returning a `size` x `size`
+/// image. Returns `None` if the landmarks are degenerate, e.g. all in the same point.
+pub fn align_face(image: &RgbImage, landmarks: &Landmarks, size: u32) -> Option<RgbImage> {
+    align_face_with_margin(image, landmarks, size, 0.0)
+}
+
+/// Like `align_face`, but shrinks the template so that `margin` times the template size of
+/// context is kept on every side.
+pub fn align_face_with_margin(
+    image: &RgbImage,
+    landmarks: &Landmarks,
+    size: u32,
+    margin: f32,
+) -> Option<RgbImage> {
+    let scale = size as f32 / (112.0 * (1.0 + 2.0 * margin));
+    let offset = 112.0 * margin * scale;
+    let template = ARC_FACE_TEMPLATE.map(|[x, y]| [x * scale + offset, y * scale + offset]);
+    let projection = similarity_transform(landmarks, &template)?;
+
+    let mut aligned = RgbImage::new(size, size);
+    warp_into(
+        image,
+        &projection,
+        Interpolation::Bilinear,
+        Rgb([0, 0, 0]),
+        &mut aligned,
+    );
+    Some(aligned)
+}
+
+/// Least-squares similarity transform (rotation, uniform scale and translation) mapping the
+/// `src` points onto the `dst` points, following Umeyama's closed form solution in 2D.
+fn similarity_transform(src: &Landmarks, dst: &Landmarks) -> Option<Projection> {
+    let n = src.len() as f32;
+    let src_mean = src
+        .iter()
+        .fold([0.0, 0.0], |acc, p| [acc[0] + p[0] / n, acc[1] + p[1] / n]);
+    let dst_mean = dst
+        .iter()
+        .fold([0.0, 0.0], |acc, p| [acc[0] + p[0] / n, acc[1] + p[1] / n]);
+
+    let (mut dot, mut cross, mut src_var) = (0.0, 0.0, 0.0);
+    for (s, d) in src.iter().zip(dst.iter()) {
+        let (sx, sy) = (s[0] - src_mean[0], s[1] - src_mean[1]);
+        let (dx, dy) = (d[0] - dst_mean[0], d[1] - dst_mean[1]);
+        dot += sx * dx + sy * dy;
+        cross += sx * dy - sy * dx;
+        src_var += sx * sx + sy * sy;
+    }
+    if src_var <= f32::EPSILON {
+        return None;
+    }
+
+    // x' = a * x - b * y + tx, y' = b * x + a * y + ty
+    let a = dot / src_var;
+    let b = cross / src_var;
+    let tx = dst_mean[0] - (a * src_mean[0] - b * src_mean[1]);
+    let ty = dst_mean[1] - (b * src_mean[0] + a * src_mean[1]);
+
+    Projection::from_matrix([a, -b, tx, b, a, ty, 0.0, 0.0, 1.0])
+}
@@ -0,0 +1,237 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use image::{imageops, imageops::FilterType, DynamicImage, ImageFormat, RgbImage};
+use serde::{Deserialize, Serialize};
+use tracing::{instrument, warn};
+
+use crate::{
+    arcface_image::as_rgb_image,
+    error::PredictionError,
+    face_alignment::{align_face_with_margin, landmarks_from_bbox},
+    landmark_predictor::LandmarkPredictor,
+    mirrored_path,
+    post_processor::Bbox,
+    ultra_predictor::UltraPredictor,
+};
+
+pub static DEFAULT_CROP_SIZE: u32 = 112;
+pub static DEFAULT_CROP_MARGIN: f32 = 0.2;
+pub static CROPS_FOLDER_NAME: &str = "crops";
+pub static CROP_MANIFEST_FILE_NAME: &str = "manifest.jsonl";
+
+/// Image format face crops are written in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CropFormat {
+    #[default]
+    Png,
+    Jpeg,
+}
+
+impl CropFormat {
+    pub fn image_format(&self) -> ImageFormat {
+        match self {
+            CropFormat::Png => ImageFormat::Png,
+            CropFormat::Jpeg => ImageFormat::Jpeg,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CropFormat::Png => "png",
+            CropFormat::Jpeg => "jpg",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CropOptions {
+    /// Width and height of the square crops in pixels.
+    pub size: u32,
+    /// Context kept around the face on every side, as a fraction of the face size.
+    pub margin: f32,
+    pub format: CropFormat,
+    /// Align the faces to the ArcFace template instead of cropping the bounding box.
+    pub aligned: bool,
+}
+
+impl Default for CropOptions {
+    fn default() -> Self {
+        CropOptions {
+            size: DEFAULT_CROP_SIZE,
+            margin: DEFAULT_CROP_MARGIN,
+            format: CropFormat::default(),
+            aligned: false,
+        }
+    }
+}
+
+/// Manifest entry mapping a crop back to the face it was cut from.
+#[derive(Serialize, Clone, Debug)]
+pub struct CropRecord {
+    pub crop_path: PathBuf,
+    pub image_path: PathBuf,
+    /// Index of the face in the image, in order of detection confidence.
+    pub face_index: usize,
+    /// Bounding box in raw image pixel coordinates.
+    pub bbox: Bbox,
+    pub confidence: f32,
+}
+
+/// Writes the faces detected in images to disk as a dataset of crops.
+pub struct FaceCropper<'a> {
+    pub ultra_predictor: &'a UltraPredictor,
+    pub landmark_predictor: Option<&'a LandmarkPredictor>,
+    pub options: CropOptions,
+}
+
+impl FaceCropper<'_> {
+    /// Detect the faces of `file_paths` and write their crops below `output_folder`, mirroring
+    /// the folder structure below `input_root`. Images that can not be processed are skipped.
+    pub fn export(
+        &self,
+        file_paths: &[PathBuf],
+        input_root: &Path,
+        output_folder: &Path,
+    ) -> Vec<CropRecord> {
+        let mut records: Vec<CropRecord> = vec![];
+        for file_path in file_paths {
+            match self.export_image(file_path, input_root, output_folder) {
+                Ok(image_records) => records.extend(image_records),
+                Err(error) => warn!("Skipping image: {}", error),
+            }
+        }
+        records
+    }
+
+    #[instrument(level = "debug", skip(self, input_root, output_folder))]
+    fn export_image(
+        &self,
+        file_path: &Path,
+        input_root: &Path,
+        output_folder: &Path,
+    ) -> Result<Vec<CropRecord>, PredictionError> {
+        let ultra_image = self.ultra_predictor.open_image(file_path)?;
+        let ultra_output = self.ultra_predictor.run(&ultra_image)?;
+
+        // crops of photo.jpg are written to photo.jpg_0.png, photo.jpg_1.png, ..., keeping the
+        // extension so the crops of photo.png do not overwrite them
+        let image_output_path = mirrored_path(output_folder, input_root, file_path);
+        let file_name = file_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        if let Some(image_output_folder) = image_output_path.parent() {
+            fs::create_dir_all(image_output_folder)?;
+        }
+
+        // aligned crops warp the RGB image, converted once for all faces
+        let rgb_image = match self.options.aligned {
+            true => Some(as_rgb_image(&ultra_image.raw_image)),
+            false => None,
+        };
+        let mut records: Vec<CropRecord> = vec![];
+        for (face_index, (bbox, confidence)) in
+            ultra_output.bbox_with_confidences.iter().enumerate()
+        {
+            let crop = self.crop(&ultra_image.raw_image, rgb_image.as_deref(), bbox)?;
+            let crop_path = image_output_path.with_file_name(format!(
+                "{}_{}.{}",
+                file_name,
+                face_index,
+                self.options.format.extension()
+            ));
+            crop.save_with_format(&crop_path, self.options.format.image_format())
+                .map_err(PredictionError::image(&crop_path))?;
+            records.push(CropRecord {
+                crop_path,
+                image_path: file_path.to_path_buf(),
+                face_index,
+                bbox: *bbox,
+                confidence: *confidence,
+            });
+        }
+        Ok(records)
+    }
+
+    /// Crop the face in `bbox`, aligned if `rgb_image`, the RGB version of `raw_image`, is given.
+    fn crop(
+        &self,
+        raw_image: &DynamicImage,
+        rgb_image: Option<&RgbImage>,
+        bbox: &Bbox,
+    ) -> Result<RgbImage, PredictionError> {
+        let Some(rgb_image) = rgb_image else {
+            return Ok(crop_square(
+                raw_image,
+                bbox,
+                self.options.size,
+                self.options.margin,
+            ));
+        };
+
+        let landmarks = match self.landmark_predictor {
+            Some(landmark_predictor) => landmark_predictor
+                .run(raw_image, bbox)?
+                .unwrap_or_else(|| landmarks_from_bbox(bbox)),
+            None => landmarks_from_bbox(bbox),
+        };
+        // degenerate landmarks can not be aligned, fall back to the plain crop
+        Ok(align_face_with_margin(
+            rgb_image,
+            &landmarks,
+            self.options.size,
+            self.options.margin,
+        )
+        .unwrap_or_else(|| crop_square(raw_image, bbox, self.options.size, self.options.margin)))
+    }
+}
+
+/// Write the manifest of the crops as JSON Lines.
+pub fn write_crop_manifest(
+    records: &[CropRecord],
+    mut writer: impl Write,
+) -> Result<(), PredictionError> {
+    for record in records {
+        serde_json::to_writer(&mut writer, record)?;
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Crop a square around the center of `bbox` (in raw image pixel coordinates) with `margin`
+/// times the longer side of context and scale it to `size` x `size`. Parts outside the image are
+/// padded black so the face stays centered.
+pub fn crop_square(raw_image: &DynamicImage, bbox: &Bbox, size: u32, margin: f32) -> RgbImage {
+    let side = ((bbox[2] - bbox[0]).max(bbox[3] - bbox[1]) * (1.0 + 2.0 * margin)).max(1.0);
+    let center = ((bbox[0] + bbox[2]) / 2.0, (bbox[1] + bbox[3]) / 2.0);
+    let (x, y) = (
+        (center.0 - side / 2.0).round() as i64,
+        (center.1 - side / 2.0).round() as i64,
+    );
+    let side = side.round() as u32;
+
+    let (crop_x, crop_y) = (x.max(0) as u32, y.max(0) as u32);
+    let visible = raw_image.crop_imm(
+        crop_x,
+        crop_y,
+        side.saturating_sub((crop_x as i64 - x) as u32),
+        side.saturating_sub((crop_y as i64 - y) as u32),
+    );
+
+    let mut square = RgbImage::new(side, side);
+    imageops::overlay(
+        &mut square,
+        &visible.to_rgb8(),
+        crop_x as i64 - x,
+        crop_y as i64 - y,
+    );
+    imageops::resize(&square, size, size, FilterType::Triangle)
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    metric::DistanceMetric,
+    vector_index::{Neighbour, VectorIndex},
+};
+
+/// Exact index comparing the query against every embedding. Best for galleries of up to some
+/// ten thousand faces and as the reference for the approximate indexes.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FlatIndex {
+    ids: Vec<u64>,
+    embeddings: Vec<Vec<f32>>,
+    positions: HashMap<u64, usize>,
+}
+
+impl FlatIndex {
+    pub fn new() -> FlatIndex {
+        FlatIndex::default()
+    }
+}
+
+impl VectorIndex for FlatIndex {
+    fn insert(&mut self, id: u64, embedding: Vec<f32>) {
+        match self.positions.get(&id) {
+            Some(&position) => self.embeddings[position] = embedding,
+            None => {
+                self.positions.insert(id, self.ids.len());
+                self.ids.push(id);
+                self.embeddings.push(embedding);
+            }
+        }
+    }
+
+    fn remove(&mut self, id: u64) -> bool {
+        let position = match self.positions.remove(&id) {
+            Some(position) => position,
+            None => return false,
+        };
+        self.ids.swap_remove(position);
+        self.embeddings.swap_remove(position);
+        // the last entry moved into the freed position
+        if let Some(&moved_id) = self.ids.get(position) {
+            self.positions.insert(moved_id, position);
+        }
+        true
+    }
+
+    fn search(&self, query: &[f32], k: usize) -> Vec<Neighbour> {
+        let mut neighbours: Vec<Neighbour> = self
+            .ids
+            .par_iter()
+            .zip(self.embeddings.par_iter())
+            .map(|(&id, embedding)| Neighbour {
+                id,
+                distance: DistanceMetric::SquaredEuclidean.distance(query, embedding),
+            })
+            .collect();
+        if k == 0 {
+            return vec![];
+        }
+        if neighbours.len() > k {
+            neighbours.select_nth_unstable_by(k - 1, |a, b| a.distance.total_cmp(&b.distance));
+            neighbours.truncate(k);
+        }
+        neighbours.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        neighbours
+    }
+
+    fn embedding(&self, id: u64) -> Option<&[f32]> {
+        self.positions
+            .get(&id)
+            .map(|&position| self.embeddings[position].as_slice())
+    }
+
+    fn len(&self) -> usize {
+        self.ids.len()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Deterministic L2 normalized vectors from a splitmix64 sequence.
+    pub(crate) fn random_embeddings(count: usize, dimensions: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut state = seed;
+        let mut next = move || {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^= z >> 31;
+            (z >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+        };
+        (0..count)
+            .map(|_| {
+                let embedding: Vec<f32> = (0..dimensions).map(|_| next()).collect();
+                let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+                embedding.into_iter().map(|x| x / norm).collect()
+            })
+            .collect()
+    }
+
+    fn ids(neighbours: &[Neighbour]) -> Vec<u64> {
+        neighbours.iter().map(|neighbour| neighbour.id).collect()
+    }
+
+    #[test]
+    fn search_returns_closest_sorted() {
+        let mut index = FlatIndex::new();
+        for (id, embedding) in random_embeddings(100, 16, 1).into_iter().enumerate() {
+            index.insert(id as u64, embedding);
+        }
+        let query = random_embeddings(1, 16, 2).remove(0);
+
+        let neighbours = index.search(&query, 10);
+        assert_eq!(neighbours.len(), 10);
+        assert!(neighbours
+            .windows(2)
+            .all(|pair| pair[0].distance <= pair[1].distance));
+        let all = index.search(&query, index.len());
+        assert_eq!(ids(&neighbours), ids(&all[..10]));
+        assert!(index.search(&query, 0).is_empty());
+    }
+
+    #[test]
+    fn remove_keeps_moved_entries_reachable() {
+        let embeddings = random_embeddings(10, 8, 3);
+        let mut index = FlatIndex::new();
+        for (id, embedding) in embeddings.iter().enumerate() {
+            index.insert(id as u64, embedding.clone());
+        }
+
+        assert!(index.remove(0));
+        assert!(!index.remove(0));
+        assert_eq!(index.len(), 9);
+        assert_eq!(index.embedding(0), None);
+        // the last entry was swapped into the freed position
+        assert_eq!(index.embedding(9), Some(embeddings[9].as_slice()));
+        let neighbours = index.search(&embeddings[9], 1);
+        assert_eq!(ids(&neighbours), vec![9]);
+
+        index.insert(0, embeddings[0].clone());
+        assert_eq!(ids(&index.search(&embeddings[0], 1)), vec![0]);
+    }
+
+    #[test]
+    fn insert_replaces_embedding() {
+        let embeddings = random_embeddings(2, 8, 4);
+        let mut index = FlatIndex::new();
+        index.insert(7, embeddings[0].clone());
+        index.insert(7, embeddings[1].clone());
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.embedding(7), Some(embeddings[1].as_slice()));
+    }
+}
@@ -0,0 +1,285 @@
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, BTreeSet, HashMap},
+    error::Error,
+    fmt, fs,
+    hash::{BuildHasher, Hasher},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::PredictionError,
+    face::{Face, ImageFaces},
+    identity::{closest_identity, Identity, IdentityMatch},
+    metric::FaceMatcher,
+    post_processor::Bbox,
+    write_atomically,
+};
+
+/// Version of the on-disk gallery format, bumped whenever the layout changes.
+pub static GALLERY_VERSION: u32 = 3;
+pub static GALLERY_FILE_NAME: &str = "gallery.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GalleryRecord {
+    pub image_path: PathBuf,
+    /// Bounding box in raw image pixel coordinates.
+    pub bbox: Bbox,
+    pub confidence: f32,
+    /// L2 normalized ArcFace embedding.
+    pub embedding: Vec<f32>,
+}
+
+impl GalleryRecord {
+    pub fn from_image_faces(image_faces: ImageFaces) -> Vec<GalleryRecord> {
+        let image_path = image_faces.image_path;
+        image_faces
+            .faces
+            .into_iter()
+            .map(|face| GalleryRecord {
+                image_path: image_path.clone(),
+                bbox: face.bbox,
+                confidence: face.confidence,
+                embedding: face.embedding,
+            })
+            .collect()
+    }
+}
+
+/// Modification time and size of an indexed file, used to detect changes between runs.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FileStamp {
+    pub modified: SystemTime,
+    pub size: u64,
+}
+
+impl FileStamp {
+    pub fn from_path(path: &Path) -> io::Result<FileStamp> {
+        let metadata = fs::metadata(path)?;
+        Ok(FileStamp {
+            modified: metadata.modified()?,
+            size: metadata.len(),
+        })
+    }
+}
+
+/// Face embeddings of an image collection, all produced by the same ArcFace model.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Gallery {
+    pub version: u32,
+    pub model_id: String,
+    /// Random id of this gallery. A gallery built again from scratch, e.g. after gallery.json was
+    /// deleted to apply other detection settings, gets a new id even if the file stamps stay the
+    /// same, so data derived from the old gallery can tell the difference.
+    #[serde(default)]
+    pub build_id: u64,
+    /// Canonical paths of the folders indexed into the gallery.
+    #[serde(default)]
+    pub folders: BTreeSet<PathBuf>,
+    /// Every processed file, including the ones without any faces.
+    pub files: BTreeMap<PathBuf, FileStamp>,
+    /// Files that could not be processed, only retried once their stamp changes.
+    #[serde(default)]
+    pub failed: BTreeMap<PathBuf, FileStamp>,
+    pub records: Vec<GalleryRecord>,
+    /// Enrolled identities by name.
+    #[serde(default)]
+    pub identities: BTreeMap<String, Identity>,
+}
+
+#[derive(Debug)]
+pub enum GalleryError {
+    UnsupportedVersion(u32),
+    ModelMismatch { expected: String, found: String },
+}
+
+impl fmt::Display for GalleryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GalleryError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported gallery version {}, expected {}",
+                version, GALLERY_VERSION
+            ),
+            GalleryError::ModelMismatch { expected, found } => write!(
+                f,
+                "gallery embeddings were produced by model {}, not {}",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl Error for GalleryError {}
+
+impl Gallery {
+    pub fn new(model_id: &str) -> Gallery {
+        Gallery {
+            version: GALLERY_VERSION,
+            model_id: model_id.to_string(),
+            build_id: new_build_id(),
+            folders: BTreeSet::new(),
+            files: BTreeMap::new(),
+            failed: BTreeMap::new(),
+            records: vec![],
+            identities: BTreeMap::new(),
+        }
+    }
+
+    /// Load a gallery from disk, refusing it if it was built with a different model.
+    pub fn load(path: &Path, model_id: &str) -> Result<Gallery, PredictionError> {
+        let reader = BufReader::new(fs::File::open(path)?);
+        let gallery: Gallery = serde_json::from_reader(reader)?;
+
+        if gallery.version != GALLERY_VERSION {
+            return Err(GalleryError::UnsupportedVersion(gallery.version).into());
+        }
+        gallery.check_model(model_id)?;
+        Ok(gallery)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), PredictionError> {
+        write_atomically(path, |writer| Ok(serde_json::to_writer(writer, self)?))
+    }
+
+    /// Add records embedded with the model `model_id`.
+    pub fn extend(
+        &mut self,
+        model_id: &str,
+        records: Vec<GalleryRecord>,
+    ) -> Result<(), GalleryError> {
+        self.check_model(model_id)?;
+        self.records.extend(records);
+        Ok(())
+    }
+
+    /// Enroll the face of `image_path` embedded with the model `model_id` as a reference of the
+    /// identity `name`, creating the identity if needed.
+    pub fn enroll(
+        &mut self,
+        model_id: &str,
+        name: &str,
+        image_path: &Path,
+        embedding: Vec<f32>,
+    ) -> Result<(), GalleryError> {
+        self.check_model(model_id)?;
+        self.identities
+            .entry(name.to_string())
+            .or_insert_with(|| Identity::new(name))
+            .add_reference(image_path.to_path_buf(), embedding);
+        Ok(())
+    }
+
+    /// Closest enrolled identity to `embedding`, `None` if no identities are enrolled.
+    pub fn identify(&self, embedding: &[f32], matcher: &FaceMatcher) -> Option<IdentityMatch> {
+        closest_identity(&self.identities, embedding, matcher)
+    }
+
+    /// Record that `image_path` has been processed in the state described by `stamp`.
+    pub fn mark_indexed(&mut self, image_path: &Path, stamp: FileStamp) {
+        self.failed.remove(image_path);
+        self.files.insert(image_path.to_path_buf(), stamp);
+    }
+
+    /// Record that processing `image_path` in the state described by `stamp` failed.
+    pub fn mark_failed(&mut self, image_path: &Path, stamp: FileStamp) {
+        self.files.remove(image_path);
+        self.failed.insert(image_path.to_path_buf(), stamp);
+    }
+
+    /// Stamp of `image_path` when it was last processed, successfully or not.
+    pub fn stamp(&self, image_path: &Path) -> Option<&FileStamp> {
+        self.files
+            .get(image_path)
+            .or_else(|| self.failed.get(image_path))
+    }
+
+    /// Remove an image and all of its faces from the gallery.
+    pub fn remove_image(&mut self, image_path: &Path) {
+        self.files.remove(image_path);
+        self.failed.remove(image_path);
+        self.records
+            .retain(|record| record.image_path != image_path);
+    }
+
+    /// Faces grouped per image, images without faces are left out.
+    pub fn images_with_faces(&self) -> Vec<ImageFaces> {
+        let mut images: BTreeMap<&Path, Vec<Face>> = BTreeMap::new();
+        for record in &self.records {
+            images
+                .entry(record.image_path.as_path())
+                .or_default()
+                .push(Face {
+                    bbox: record.bbox,
+                    confidence: record.confidence,
+                    embedding: record.embedding.clone(),
+                });
+        }
+        images
+            .into_iter()
+            .map(|(image_path, faces)| ImageFaces {
+                image_path: image_path.to_path_buf(),
+                faces,
+            })
+            .collect()
+    }
+
+    fn check_model(&self, model_id: &str) -> Result<(), GalleryError> {
+        if self.model_id != model_id {
+            return Err(GalleryError::ModelMismatch {
+                expected: model_id.to_string(),
+                found: self.model_id.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Random id from the randomly seeded std hasher and the current time.
+fn new_build_id() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(elapsed) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(elapsed.as_nanos());
+    }
+    hasher.finish()
+}
+
+static MODEL_IDS: OnceLock<Mutex<HashMap<PathBuf, String>>> = OnceLock::new();
+
+/// Identify a model file by its name and a FNV-1a hash of its contents.
+///
+/// Hashing reads the whole model, so the id is computed once per model path and process and
+/// shared by all predictors of the model, e.g. the sessions of a predictor pool.
+pub fn model_id(model_filepath: &Path) -> io::Result<String> {
+    let model_ids = MODEL_IDS.get_or_init(Default::default);
+    let cache_key = fs::canonicalize(model_filepath)?;
+    if let Some(model_id) = model_ids
+        .lock()
+        .ok()
+        .and_then(|model_ids| model_ids.get(&cache_key).cloned())
+    {
+        return Ok(model_id);
+    }
+
+    let model_id = hash_model(model_filepath)?;
+    if let Ok(mut model_ids) = model_ids.lock() {
+        model_ids.insert(cache_key, model_id.clone());
+    }
+    Ok(model_id)
+}
+
+fn hash_model(model_filepath: &Path) -> io::Result<String> {
+    let bytes = fs::read(model_filepath)?;
+    let hash = bytes.iter().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+    let file_name = model_filepath
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    Ok(format!("{}-{:016x}", file_name, hash))
+}
@@ -0,0 +1,435 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    metric::DistanceMetric,
+    vector_index::{Neighbour, VectorIndex},
+};
+
+pub static DEFAULT_HNSW_M: usize = 16;
+pub static DEFAULT_HNSW_EF_CONSTRUCTION: usize = 200;
+pub static DEFAULT_HNSW_EF_SEARCH: usize = 64;
+/// Seed of the level generator, fixed so that building the same gallery gives the same graph.
+static LEVEL_SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HnswParams {
+    /// Links per node on the upper layers, twice as many on the bottom layer.
+    pub m: usize,
+    /// Candidates considered while linking a new node, higher builds a better graph slower.
+    pub ef_construction: usize,
+    /// Candidates considered per query, higher improves recall at the cost of speed.
+    pub ef_search: usize,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        HnswParams {
+            m: DEFAULT_HNSW_M,
+            ef_construction: DEFAULT_HNSW_EF_CONSTRUCTION,
+            ef_search: DEFAULT_HNSW_EF_SEARCH,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Node {
+    id: u64,
+    embedding: Vec<f32>,
+    /// Neighbours on every layer the node is part of, from the bottom layer up.
+    links: Vec<Vec<usize>>,
+    deleted: bool,
+}
+
+/// Approximate index on a hierarchical navigable small world graph (Malkov and Yashunin).
+///
+/// Queries visit a small part of the gallery, so they stay fast for hundreds of thousands of
+/// faces at the cost of occasionally missing a true neighbour. Removed embeddings are only marked
+/// as deleted and keep guiding the search, `compact` rebuilds the graph without them.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HnswIndex {
+    params: HnswParams,
+    nodes: Vec<Node>,
+    positions: HashMap<u64, usize>,
+    entry_point: Option<usize>,
+    max_layer: usize,
+    deleted: usize,
+    level_state: u64,
+}
+
+/// Node with its distance to the query, ordered by distance.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Candidate {
+    distance: f32,
+    node: usize,
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then(self.node.cmp(&other.node))
+    }
+}
+
+impl HnswIndex {
+    pub fn new(params: HnswParams) -> HnswIndex {
+        HnswIndex {
+            params,
+            nodes: vec![],
+            positions: HashMap::new(),
+            entry_point: None,
+            max_layer: 0,
+            deleted: 0,
+            level_state: LEVEL_SEED,
+        }
+    }
+
+    pub fn params(&self) -> HnswParams {
+        self.params
+    }
+
+    /// Number of removed embeddings still in the graph.
+    pub fn deleted(&self) -> usize {
+        self.deleted
+    }
+
+    /// Rebuild the graph from the embeddings that have not been removed.
+    pub fn compact(&mut self) {
+        let nodes = std::mem::take(&mut self.nodes);
+        *self = HnswIndex::new(self.params);
+        for node in nodes.into_iter().filter(|node| !node.deleted) {
+            self.insert(node.id, node.embedding);
+        }
+    }
+
+    fn distance(&self, query: &[f32], node: usize) -> f32 {
+        DistanceMetric::SquaredEuclidean.distance(query, &self.nodes[node].embedding)
+    }
+
+    fn max_links(&self, layer: usize) -> usize {
+        if layer == 0 {
+            2 * self.params.m
+        } else {
+            self.params.m
+        }
+    }
+
+    /// Layer of a new node, exponentially less likely the higher it is.
+    fn random_level(&mut self) -> usize {
+        // splitmix64, good enough for level assignment and without a dependency
+        self.level_state = self.level_state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.level_state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        let uniform = ((z >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        let level_multiplier = 1.0 / (self.params.m.max(2) as f64).ln();
+        (-uniform.ln() * level_multiplier).floor() as usize
+    }
+
+    /// Closest node to `query` on `layer`, following the links greedily from `entry`.
+    fn greedy_closest(&self, query: &[f32], entry: usize, layer: usize) -> usize {
+        let mut closest = Candidate {
+            distance: self.distance(query, entry),
+            node: entry,
+        };
+        loop {
+            let mut improved = false;
+            for &neighbour in &self.nodes[closest.node].links[layer] {
+                let distance = self.distance(query, neighbour);
+                if distance < closest.distance {
+                    closest = Candidate {
+                        distance,
+                        node: neighbour,
+                    };
+                    improved = true;
+                }
+            }
+            if !improved {
+                return closest.node;
+            }
+        }
+    }
+
+    /// Up to `ef` nodes closest to `query` on `layer`, sorted by increasing distance.
+    fn search_layer(&self, query: &[f32], entry: usize, ef: usize, layer: usize) -> Vec<Candidate> {
+        let entry = Candidate {
+            distance: self.distance(query, entry),
+            node: entry,
+        };
+        let mut visited: HashSet<usize> = HashSet::from([entry.node]);
+        let mut candidates: BinaryHeap<Reverse<Candidate>> = BinaryHeap::from([Reverse(entry)]);
+        let mut results: BinaryHeap<Candidate> = BinaryHeap::from([entry]);
+
+        while let Some(Reverse(candidate)) = candidates.pop() {
+            let furthest = results
+                .peek()
+                .map_or(f32::INFINITY, |result| result.distance);
+            if candidate.distance > furthest && results.len() >= ef {
+                break;
+            }
+            for &neighbour in &self.nodes[candidate.node].links[layer] {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+                let neighbour = Candidate {
+                    distance: self.distance(query, neighbour),
+                    node: neighbour,
+                };
+                let furthest = results
+                    .peek()
+                    .map_or(f32::INFINITY, |result| result.distance);
+                if results.len() < ef || neighbour.distance < furthest {
+                    candidates.push(Reverse(neighbour));
+                    results.push(neighbour);
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+        results.into_sorted_vec()
+    }
+
+    /// Link `node` to `neighbour` on `layer`, dropping the furthest link of `neighbour` if it has
+    /// too many.
+    fn add_link(&mut self, neighbour: usize, node: usize, layer: usize) {
+        self.nodes[neighbour].links[layer].push(node);
+        if self.nodes[neighbour].links[layer].len() <= self.max_links(layer) {
+            return;
+        }
+        let embedding = self.nodes[neighbour].embedding.clone();
+        let mut links: Vec<Candidate> = self.nodes[neighbour].links[layer]
+            .iter()
+            .map(|&link| Candidate {
+                distance: self.distance(&embedding, link),
+                node: link,
+            })
+            .collect();
+        links.sort();
+        links.truncate(self.max_links(layer));
+        self.nodes[neighbour].links[layer] = links.into_iter().map(|link| link.node).collect();
+    }
+}
+
+impl Default for HnswIndex {
+    fn default() -> Self {
+        HnswIndex::new(HnswParams::default())
+    }
+}
+
+impl VectorIndex for HnswIndex {
+    fn insert(&mut self, id: u64, embedding: Vec<f32>) {
+        self.remove(id);
+
+        let node = self.nodes.len();
+        let level = self.random_level();
+        self.nodes.push(Node {
+            id,
+            embedding,
+            links: vec![vec![]; level + 1],
+            deleted: false,
+        });
+        self.positions.insert(id, node);
+
+        let mut entry = match self.entry_point {
+            Some(entry) => entry,
+            None => {
+                self.entry_point = Some(node);
+                self.max_layer = level;
+                return;
+            }
+        };
+
+        let query = self.nodes[node].embedding.clone();
+        for layer in (level + 1..=self.max_layer).rev() {
+            entry = self.greedy_closest(&query, entry, layer);
+        }
+        for layer in (0..=level.min(self.max_layer)).rev() {
+            let candidates = self.search_layer(&query, entry, self.params.ef_construction, layer);
+            let neighbours: Vec<usize> = candidates
+                .iter()
+                .take(self.max_links(layer))
+                .map(|candidate| candidate.node)
+                .collect();
+            for &neighbour in &neighbours {
+                self.add_link(neighbour, node, layer);
+            }
+            self.nodes[node].links[layer] = neighbours;
+            entry = candidates[0].node;
+        }
+
+        if level > self.max_layer {
+            self.entry_point = Some(node);
+            self.max_layer = level;
+        }
+    }
+
+    fn remove(&mut self, id: u64) -> bool {
+        match self.positions.remove(&id) {
+            Some(node) => {
+                self.nodes[node].deleted = true;
+                self.deleted += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn search(&self, query: &[f32], k: usize) -> Vec<Neighbour> {
+        let mut entry = match self.entry_point {
+            Some(entry) if k > 0 => entry,
+            _ => return vec![],
+        };
+        for layer in (1..=self.max_layer).rev() {
+            entry = self.greedy_closest(query, entry, layer);
+        }
+        // deleted nodes still take up candidate slots, widen the search to make up for them
+        let ef = (self.params.ef_search.max(k) * self.nodes.len()) / self.len().max(1);
+        self.search_layer(query, entry, ef, 0)
+            .into_iter()
+            .filter(|candidate| !self.nodes[candidate.node].deleted)
+            .take(k)
+            .map(|candidate| Neighbour {
+                id: self.nodes[candidate.node].id,
+                distance: candidate.distance,
+            })
+            .collect()
+    }
+
+    fn embedding(&self, id: u64) -> Option<&[f32]> {
+        self.positions
+            .get(&id)
+            .map(|&node| self.nodes[node].embedding.as_slice())
+    }
+
+    fn len(&self) -> usize {
+        self.positions.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::flat_index::{tests::random_embeddings, FlatIndex};
+
+    static DIMENSIONS: usize = 32;
+    static K: usize = 10;
+
+    fn build(embeddings: &[Vec<f32>]) -> (HnswIndex, FlatIndex) {
+        let mut hnsw = HnswIndex::default();
+        let mut flat = FlatIndex::new();
+        for (id, embedding) in embeddings.iter().enumerate() {
+            hnsw.insert(id as u64, embedding.clone());
+            flat.insert(id as u64, embedding.clone());
+        }
+        (hnsw, flat)
+    }
+
+    /// Fraction of the exact top k neighbours found by `hnsw`.
+    fn recall(hnsw: &HnswIndex, flat: &FlatIndex, queries: &[Vec<f32>]) -> f32 {
+        let mut found = 0;
+        for query in queries {
+            let expected: HashSet<u64> = flat.search(query, K).iter().map(|n| n.id).collect();
+            found += hnsw
+                .search(query, K)
+                .iter()
+                .filter(|neighbour| expected.contains(&neighbour.id))
+                .count();
+        }
+        found as f32 / (queries.len() * K) as f32
+    }
+
+    #[test]
+    fn recall_against_flat_index() {
+        let (hnsw, flat) = build(&random_embeddings(1000, DIMENSIONS, 10));
+        let queries = random_embeddings(100, DIMENSIONS, 11);
+
+        let recall = recall(&hnsw, &flat, &queries);
+        assert!(recall >= 0.95, "recall {}", recall);
+        for query in &queries {
+            let neighbours = hnsw.search(query, K);
+            assert_eq!(neighbours.len(), K);
+            assert!(neighbours
+                .windows(2)
+                .all(|pair| pair[0].distance <= pair[1].distance));
+        }
+    }
+
+    #[test]
+    fn remove_reinsert_and_compact() {
+        let embeddings = random_embeddings(500, DIMENSIONS, 20);
+        let (mut hnsw, mut flat) = build(&embeddings);
+        let queries = random_embeddings(50, DIMENSIONS, 21);
+
+        let removed: Vec<u64> = (0..500).step_by(2).collect();
+        for id in &removed {
+            assert!(hnsw.remove(*id));
+            flat.remove(*id);
+        }
+        assert!(!hnsw.remove(0));
+        assert_eq!(hnsw.len(), 250);
+        assert_eq!(hnsw.deleted(), 250);
+        assert_eq!(hnsw.embedding(0), None);
+        for query in &queries {
+            assert!(hnsw
+                .search(query, K)
+                .iter()
+                .all(|neighbour| neighbour.id % 2 == 1));
+        }
+        assert!(recall(&hnsw, &flat, &queries) >= 0.9);
+
+        // re-inserted ids come back with their new embedding
+        let replacements = random_embeddings(10, DIMENSIONS, 22);
+        for (id, embedding) in removed.iter().zip(&replacements) {
+            hnsw.insert(*id, embedding.clone());
+            flat.insert(*id, embedding.clone());
+            assert_eq!(hnsw.search(embedding, 1)[0].id, *id);
+        }
+        assert_eq!(hnsw.len(), 260);
+
+        hnsw.compact();
+        assert_eq!(hnsw.deleted(), 0);
+        assert_eq!(hnsw.len(), 260);
+        assert_eq!(hnsw.embedding(removed[0]), Some(replacements[0].as_slice()));
+        assert!(recall(&hnsw, &flat, &queries) >= 0.95);
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let (mut hnsw, _) = build(&random_embeddings(300, DIMENSIONS, 30));
+        hnsw.remove(5);
+        let queries = random_embeddings(20, DIMENSIONS, 31);
+
+        let bytes = bincode::serialize(&hnsw).unwrap();
+        let mut restored: HnswIndex = bincode::deserialize(&bytes).unwrap();
+
+        assert_eq!(restored.len(), hnsw.len());
+        assert_eq!(restored.deleted(), hnsw.deleted());
+        for query in &queries {
+            assert_eq!(restored.search(query, K), hnsw.search(query, K));
+        }
+        // the level generator state survives too, so both graphs keep growing the same way
+        let embedding = random_embeddings(1, DIMENSIONS, 32).remove(0);
+        hnsw.insert(1000, embedding.clone());
+        restored.insert(1000, embedding);
+        for query in &queries {
+            assert_eq!(restored.search(query, K), hnsw.search(query, K));
+        }
+    }
+}
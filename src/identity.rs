@@ -0,0 +1,217 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use ndarray::ArrayView1;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    face::ImageFaces,
+    metric::{cosine_similarity, FaceMatcher},
+    normalize_embedding,
+    post_processor::Bbox,
+};
+
+/// Label of faces that do not match any enrolled identity.
+pub static UNKNOWN_IDENTITY: &str = "unknown";
+
+/// A named person enrolled with one or more reference images.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Identity {
+    pub name: String,
+    /// Reference images, in the same order as `embeddings`.
+    pub reference_images: Vec<PathBuf>,
+    /// L2 normalized embeddings of the reference faces.
+    pub embeddings: Vec<Vec<f32>>,
+    /// L2 normalized mean of `embeddings`, matched against during identification.
+    pub template: Vec<f32>,
+}
+
+impl Identity {
+    pub fn new(name: &str) -> Identity {
+        Identity {
+            name: name.to_string(),
+            reference_images: vec![],
+            embeddings: vec![],
+            template: vec![],
+        }
+    }
+
+    /// Add a reference face, replacing the previous one of `image_path`.
+    pub fn add_reference(&mut self, image_path: PathBuf, embedding: Vec<f32>) {
+        match self
+            .reference_images
+            .iter()
+            .position(|reference_image| *reference_image == image_path)
+        {
+            Some(index) => self.embeddings[index] = embedding,
+            None => {
+                self.reference_images.push(image_path);
+                self.embeddings.push(embedding);
+            }
+        }
+        self.update_template();
+    }
+
+    fn update_template(&mut self) {
+        let mut sum = vec![0.0; self.embeddings.first().map_or(0, Vec::len)];
+        for embedding in &self.embeddings {
+            for (total, value) in sum.iter_mut().zip(embedding) {
+                *total += value;
+            }
+        }
+        self.template = normalize_embedding(sum);
+    }
+}
+
+/// Closest enrolled identity to a face.
+#[derive(Clone, Debug)]
+pub struct IdentityMatch {
+    pub name: String,
+    /// Distance to the identity template in the metric of the `FaceMatcher`.
+    pub distance: f32,
+    pub cosine_similarity: f32,
+    /// Whether the distance is within the match threshold.
+    pub is_match: bool,
+}
+
+impl IdentityMatch {
+    /// Name of the identity, or `UNKNOWN_IDENTITY` if the face does not match it.
+    pub fn label(&self) -> &str {
+        if self.is_match {
+            &self.name
+        } else {
+            UNKNOWN_IDENTITY
+        }
+    }
+}
+
+/// Find the identity whose template is closest to `embedding`, `None` if there are no
+/// identities. The face matches it if `matcher` accepts the distance.
+pub fn closest_identity(
+    identities: &BTreeMap<String, Identity>,
+    embedding: &[f32],
+    matcher: &FaceMatcher,
+) -> Option<IdentityMatch> {
+    identities
+        .values()
+        .filter(|identity| identity.template.len() == embedding.len())
+        .map(|identity| {
+            let distance = matcher.distance(&identity.template, embedding);
+            IdentityMatch {
+                name: identity.name.clone(),
+                distance,
+                cosine_similarity: cosine_similarity(
+                    ArrayView1::from(&identity.template),
+                    ArrayView1::from(embedding),
+                ),
+                is_match: matcher.is_match(distance),
+            }
+        })
+        .min_by(|a, b| a.distance.total_cmp(&b.distance))
+}
+
+/// A face labelled with the identity it matches.
+#[derive(Serialize, Clone, Debug)]
+pub struct IdentifiedFace {
+    pub image_path: PathBuf,
+    /// Index of the face in the image, in order of detection confidence.
+    pub face_index: usize,
+    /// Bounding box in raw image pixel coordinates.
+    pub bbox: Bbox,
+    /// Detection confidence of the face.
+    pub confidence: f32,
+    /// Name of the matching identity, or `UNKNOWN_IDENTITY`.
+    pub identity: String,
+    /// Distance to the closest identity template, even if it does not match.
+    pub distance: f32,
+    pub cosine_similarity: f32,
+    /// Whether the distance is within the match threshold.
+    pub is_match: bool,
+}
+
+/// Label every face of `images` with its closest identity. Returns no faces if there are no
+/// identities.
+pub fn identify_faces(
+    images: &[ImageFaces],
+    identities: &BTreeMap<String, Identity>,
+    matcher: &FaceMatcher,
+) -> Vec<IdentifiedFace> {
+    images
+        .iter()
+        .flat_map(|image| {
+            image
+                .faces
+                .iter()
+                .enumerate()
+                .filter_map(|(face_index, face)| {
+                    let identity_match = closest_identity(identities, &face.embedding, matcher)?;
+                    Some(IdentifiedFace {
+                        image_path: image.image_path.clone(),
+                        face_index,
+                        bbox: face.bbox,
+                        confidence: face.confidence,
+                        identity: identity_match.label().to_string(),
+                        distance: identity_match.distance,
+                        cosine_similarity: identity_match.cosine_similarity,
+                        is_match: identity_match.is_match,
+                    })
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(values: &[f32], expected: &[f32]) {
+        assert_eq!(values.len(), expected.len());
+        for (value, expected) in values.iter().zip(expected) {
+            assert!(
+                (value - expected).abs() < 1e-6,
+                "{:?} != {:?}",
+                values,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn template_is_the_normalized_mean_of_the_references() {
+        let mut identity = Identity::new("ada");
+        identity.add_reference(PathBuf::from("a.jpg"), vec![1.0, 0.0]);
+        assert_close(&identity.template, &[1.0, 0.0]);
+
+        identity.add_reference(PathBuf::from("b.jpg"), vec![0.0, 1.0]);
+        let component = 0.5_f32.sqrt();
+        assert_close(&identity.template, &[component, component]);
+    }
+
+    #[test]
+    fn adding_an_image_again_replaces_its_reference() {
+        let mut identity = Identity::new("ada");
+        identity.add_reference(PathBuf::from("a.jpg"), vec![1.0, 0.0]);
+        identity.add_reference(PathBuf::from("b.jpg"), vec![0.0, 1.0]);
+        identity.add_reference(PathBuf::from("b.jpg"), vec![1.0, 0.0]);
+
+        assert_eq!(identity.reference_images.len(), 2);
+        assert_eq!(identity.embeddings, vec![vec![1.0, 0.0], vec![1.0, 0.0]]);
+        assert_close(&identity.template, &[1.0, 0.0]);
+    }
+
+    #[test]
+    fn faces_far_from_every_template_are_unknown() {
+        let mut identities = BTreeMap::new();
+        for (name, embedding) in [("ada", vec![1.0, 0.0]), ("grace", vec![0.0, 1.0])] {
+            let mut identity = Identity::new(name);
+            identity.add_reference(PathBuf::from(format!("{}.jpg", name)), embedding);
+            identities.insert(name.to_string(), identity);
+        }
+        let matcher = FaceMatcher::default();
+
+        let closest = closest_identity(&identities, &[0.1, 0.995], &matcher).unwrap();
+        assert_eq!(closest.label(), "grace");
+        let closest = closest_identity(&identities, &[0.3, -0.954], &matcher).unwrap();
+        assert_eq!(closest.name, "ada");
+        assert_eq!(closest.label(), UNKNOWN_IDENTITY);
+    }
+}
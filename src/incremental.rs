@@ -0,0 +1,160 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::PredictionError,
+    gallery::{FileStamp, Gallery},
+    get_file_paths_from_folder,
+    pipeline::Pipeline,
+    process_file_paths_into_gallery,
+};
+
+/// Difference between the files of a folder and the files indexed in a gallery.
+#[derive(Debug, Default)]
+pub struct FolderChanges {
+    pub added: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    pub deleted: Vec<PathBuf>,
+}
+
+impl FolderChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.modified.is_empty() && self.deleted.is_empty()
+    }
+}
+
+/// Compare `file_paths` of the folder `folder_path` against the files processed into `gallery`
+/// using modification time and size. Only processed files below `folder_path` can be deleted, so
+/// several folders can share a gallery. Paths are compared as given, see `update_gallery`.
+pub fn detect_changes(
+    gallery: &Gallery,
+    folder_path: &Path,
+    file_paths: &[PathBuf],
+) -> io::Result<FolderChanges> {
+    let mut changes = FolderChanges::default();
+
+    for file_path in file_paths {
+        match gallery.stamp(file_path) {
+            None => changes.added.push(file_path.clone()),
+            Some(stamp) if *stamp != FileStamp::from_path(file_path)? => {
+                changes.modified.push(file_path.clone())
+            }
+            Some(_) => (),
+        }
+    }
+
+    let current_paths: HashSet<&PathBuf> = file_paths.iter().collect();
+    changes.deleted = gallery
+        .files
+        .keys()
+        .chain(gallery.failed.keys())
+        .filter(|processed_path| {
+            processed_path.starts_with(folder_path) && !current_paths.contains(processed_path)
+        })
+        .cloned()
+        .collect();
+
+    Ok(changes)
+}
+
+/// Bring `gallery` up to date with `folder_path`, only running the predictors on added and
+/// modified files. Returns the changes that were applied.
+///
+/// The folder is canonicalized first, so the same folder given as `photos` or `./photos` maps to
+/// the same gallery entries.
+pub fn update_gallery(
+    gallery: &mut Gallery,
+    folder_path: &Path,
+    pipeline: &Pipeline,
+) -> Result<FolderChanges, PredictionError> {
+    let folder_path = fs::canonicalize(folder_path)?;
+    let file_paths = get_file_paths_from_folder(&folder_path)?;
+    let changes = detect_changes(gallery, &folder_path, &file_paths)?;
+    gallery.folders.insert(folder_path);
+
+    for file_path in changes.modified.iter().chain(changes.deleted.iter()) {
+        gallery.remove_image(file_path);
+    }
+
+    let changed_paths: Vec<PathBuf> = changes
+        .added
+        .iter()
+        .chain(changes.modified.iter())
+        .cloned()
+        .collect();
+    process_file_paths_into_gallery(&changed_paths, pipeline, gallery)?;
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    /// Empty folder in the temporary directory, unique per test and process.
+    fn test_folder(name: &str) -> PathBuf {
+        let folder_path = env::temp_dir().join(format!("incremental-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&folder_path);
+        fs::create_dir_all(&folder_path).unwrap();
+        folder_path
+    }
+
+    fn indexed(gallery: &mut Gallery, file_path: &Path) {
+        gallery.mark_indexed(file_path, FileStamp::from_path(file_path).unwrap());
+    }
+
+    #[test]
+    fn detect_changes_finds_added_modified_and_deleted_files() {
+        let folder_path = test_folder("changes");
+        let (unchanged, modified, deleted, added, failed) = (
+            folder_path.join("unchanged.jpg"),
+            folder_path.join("modified.jpg"),
+            folder_path.join("deleted.jpg"),
+            folder_path.join("added.jpg"),
+            folder_path.join("failed.jpg"),
+        );
+        for file_path in [&unchanged, &modified, &deleted, &failed] {
+            fs::write(file_path, b"image").unwrap();
+        }
+        let mut gallery = Gallery::new("model");
+        for file_path in [&unchanged, &modified, &deleted] {
+            indexed(&mut gallery, file_path);
+        }
+        gallery.mark_failed(&failed, FileStamp::from_path(&failed).unwrap());
+        fs::write(&modified, b"larger image").unwrap();
+        fs::remove_file(&deleted).unwrap();
+        fs::write(&added, b"image").unwrap();
+
+        let file_paths = vec![unchanged, modified.clone(), added.clone(), failed];
+        let changes = detect_changes(&gallery, &folder_path, &file_paths).unwrap();
+        fs::remove_dir_all(&folder_path).unwrap();
+
+        assert_eq!(changes.added, vec![added]);
+        assert_eq!(changes.modified, vec![modified]);
+        assert_eq!(changes.deleted, vec![deleted]);
+    }
+
+    #[test]
+    fn detect_changes_only_deletes_files_of_the_folder() {
+        let folder_path = test_folder("scope");
+        let other_folder_path = test_folder("scope-other");
+        let file_path = folder_path.join("photo.jpg");
+        let other_file_path = other_folder_path.join("photo.jpg");
+        fs::write(&file_path, b"image").unwrap();
+        fs::write(&other_file_path, b"image").unwrap();
+        let mut gallery = Gallery::new("model");
+        indexed(&mut gallery, &file_path);
+        indexed(&mut gallery, &other_file_path);
+
+        let changes = detect_changes(&gallery, &folder_path, &[file_path]).unwrap();
+        fs::remove_dir_all(&folder_path).unwrap();
+        fs::remove_dir_all(&other_folder_path).unwrap();
+
+        assert!(changes.is_empty());
+    }
+}
@@ -0,0 +1,285 @@
+use std::path::Path;
+
+use image::{imageops, imageops::FilterType, DynamicImage, GenericImageView, RgbImage};
+use ndarray::{Array4, CowArray, IxDyn};
+use ort::{tensor::OrtOwnedTensor, OrtError, Session, Value};
+use tracing::{debug, instrument};
+
+use crate::{
+    error::PredictionError,
+    face_alignment::Landmarks,
+    post_processor::{iou, Bbox},
+    runtime::SessionOptions,
+};
+
+/// Five point facial landmarks from an SCRFD face detector with keypoints, e.g. `det_10g.onnx`
+/// of the InsightFace `buffalo_l` model pack.
+///
+/// SCRFD runs on a square region around an Ultraface bounding box and the keypoints of the SCRFD
+/// detection overlapping the bounding box the most are the landmarks of the face.
+pub struct LandmarkPredictor {
+    pub name: String,
+    pub session: Session,
+    /// Width and height of the square SCRFD input, a multiple of the largest stride.
+    pub input_size: usize,
+}
+
+pub static LANDMARK_PREDICTOR_NAME: &str = "LandmarkPredictor";
+pub static LANDMARK_DEFAULT_INPUT_SIZE: usize = 192;
+/// Side of the region passed to SCRFD relative to the longer side of the bounding box, the face
+/// covers about half of the input.
+static LANDMARK_REGION_SCALE: f32 = 2.0;
+/// Feature map strides of the SCRFD models, every location has `SCRFD_ANCHORS` anchors.
+static SCRFD_STRIDES: [usize; 3] = [8, 16, 32];
+static SCRFD_ANCHORS: usize = 2;
+static SCRFD_SCORE_THRESHOLD: f32 = 0.5;
+/// SCRFD detections overlapping the bounding box less than this belong to another face.
+static SCRFD_MIN_IOU: f32 = 0.3;
+
+/// Face found by SCRFD, in input pixel coordinates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ScrfdDetection {
+    bbox: Bbox,
+    score: f32,
+    keypoints: Landmarks,
+}
+
+impl LandmarkPredictor {
+    #[instrument(level = "debug", skip(session_options))]
+    pub fn new(
+        model_filepath: &Path,
+        session_options: &SessionOptions,
+    ) -> Result<LandmarkPredictor, PredictionError> {
+        let session = session_options.build_session(model_filepath)?;
+
+        // NCHW input, use the spatial size of the model if it is fixed
+        let input_size = session
+            .inputs
+            .first()
+            .and_then(|input| input.dimensions.get(3).copied().flatten())
+            .map(|size| size as usize)
+            .unwrap_or(LANDMARK_DEFAULT_INPUT_SIZE);
+
+        Ok(LandmarkPredictor {
+            name: LANDMARK_PREDICTOR_NAME.to_string(),
+            session,
+            input_size,
+        })
+    }
+
+    /// Locate the landmarks of the face in `bbox`, both in raw image pixel coordinates. `None` if
+    /// SCRFD finds no face overlapping `bbox`.
+    #[instrument(level = "debug", skip(self, raw_image))]
+    pub fn run(
+        &self,
+        raw_image: &DynamicImage,
+        bbox: &Bbox,
+    ) -> Result<Option<Landmarks>, PredictionError> {
+        let (x, y, width, height) = face_region(bbox, raw_image.dimensions());
+        // resized keeping the aspect ratio into the top left corner of the input, like InsightFace
+        let scale = self.input_size as f32 / width.max(height) as f32;
+        let region = raw_image.crop_imm(x, y, width, height).resize_exact(
+            ((width as f32 * scale).round() as u32).max(1),
+            ((height as f32 * scale).round() as u32).max(1),
+            FilterType::Triangle,
+        );
+        let mut input_image = RgbImage::new(self.input_size as u32, self.input_size as u32);
+        imageops::replace(&mut input_image, &region.to_rgb8(), 0, 0);
+
+        let image_tensor = self.get_image_tensor(&input_image);
+        let image_input = self.get_image_input(&image_tensor)?;
+        let raw_outputs = self.session.run(image_input)?;
+        let mut outputs: Vec<(usize, Vec<f32>)> = vec![];
+        for raw_output in &raw_outputs {
+            let output: OrtOwnedTensor<f32, _> = raw_output.try_extract()?;
+            let view = output.view();
+            let last_dimension = view.shape().last().copied().unwrap_or_default();
+            outputs.push((last_dimension, view.iter().copied().collect()));
+        }
+        let detections = decode_scrfd(&outputs, self.input_size)?;
+
+        let to_input = |value: f32, origin: u32| (value - origin as f32) * scale;
+        let input_bbox = [
+            to_input(bbox[0], x),
+            to_input(bbox[1], y),
+            to_input(bbox[2], x),
+            to_input(bbox[3], y),
+        ];
+        let best = detections
+            .iter()
+            .map(|detection| (detection, iou(&detection.bbox, &input_bbox)))
+            .filter(|(_, overlap)| *overlap >= SCRFD_MIN_IOU)
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+        let Some((detection, _)) = best else {
+            debug!("No SCRFD detection overlaps the face");
+            return Ok(None);
+        };
+
+        Ok(Some(detection.keypoints.map(|[keypoint_x, keypoint_y]| {
+            [keypoint_x / scale + x as f32, keypoint_y / scale + y as f32]
+        })))
+    }
+
+    fn get_image_tensor(&self, image: &RgbImage) -> CowArray<'_, f32, IxDyn> {
+        CowArray::from(Array4::from_shape_fn(
+            (1, 3, self.input_size, self.input_size),
+            |(_, c, y, x)| (image[(x as _, y as _)][c] as f32 - 127.5) / 128.0,
+        ))
+        .into_dyn()
+    }
+
+    fn get_image_input<'a>(
+        &self,
+        image_tensor: &'a CowArray<'a, f32, IxDyn>,
+    ) -> Result<Vec<Value<'a>>, OrtError> {
+        let input_value = Value::from_array(self.session.allocator(), image_tensor)?;
+
+        Ok(vec![input_value])
+    }
+}
+
+/// Square region of `LANDMARK_REGION_SCALE` times the bounding box around its center, clipped to
+/// the image, as `(x, y, width, height)`.
+fn face_region(bbox: &Bbox, image_size: (u32, u32)) -> (u32, u32, u32, u32) {
+    let side = (bbox[2] - bbox[0]).max(bbox[3] - bbox[1]) * LANDMARK_REGION_SCALE;
+    let (center_x, center_y) = ((bbox[0] + bbox[2]) / 2.0, (bbox[1] + bbox[3]) / 2.0);
+    let clip = |center: f32, size: u32| {
+        let start = ((center - side / 2.0).max(0.0) as u32).min(size.saturating_sub(1));
+        let end = ((center + side / 2.0).max(0.0) as u32).min(size);
+        (start, end.saturating_sub(start).max(1))
+    };
+    let (x, width) = clip(center_x, image_size.0);
+    let (y, height) = clip(center_y, image_size.1);
+    (x, y, width, height)
+}
+
+/// Decode the outputs of an SCRFD model with keypoints, given as the size of their last
+/// dimension and their flattened values, into the detections above `SCRFD_SCORE_THRESHOLD`.
+///
+/// Every stride has a score (last dimension 1), a box distance (4) and a keypoint offset (10)
+/// output with one row per anchor. Outputs are matched by shape rather than position since
+/// exports order them differently.
+fn decode_scrfd(
+    outputs: &[(usize, Vec<f32>)],
+    input_size: usize,
+) -> Result<Vec<ScrfdDetection>, PredictionError> {
+    let mut detections: Vec<ScrfdDetection> = vec![];
+    for stride in SCRFD_STRIDES {
+        let cells = input_size / stride;
+        let anchors = cells * cells * SCRFD_ANCHORS;
+        let output = |columns: usize| {
+            outputs
+                .iter()
+                .find(|(last_dimension, values)| {
+                    *last_dimension == columns && values.len() == anchors * columns
+                })
+                .map(|(_, values)| values.as_slice())
+                .ok_or_else(|| {
+                    PredictionError::OutputShape(format!(
+                        "{} expected an SCRFD output of {}x{} for stride {}, is the model an \
+                         SCRFD detector with keypoints?",
+                        LANDMARK_PREDICTOR_NAME, anchors, columns, stride
+                    ))
+                })
+        };
+        let (scores, distances, offsets) = (output(1)?, output(4)?, output(10)?);
+
+        for (anchor, score) in scores.iter().enumerate() {
+            if *score < SCRFD_SCORE_THRESHOLD {
+                continue;
+            }
+            // anchors of a location are consecutive, locations are in row major order
+            let location = anchor / SCRFD_ANCHORS;
+            let center_x = ((location % cells) * stride) as f32;
+            let center_y = ((location / cells) * stride) as f32;
+            let distance = &distances[anchor * 4..anchor * 4 + 4];
+            let offset = &offsets[anchor * 10..anchor * 10 + 10];
+            let mut keypoints: Landmarks = [[0.0; 2]; 5];
+            for (keypoint, point) in keypoints.iter_mut().zip(offset.chunks_exact(2)) {
+                *keypoint = [
+                    center_x + point[0] * stride as f32,
+                    center_y + point[1] * stride as f32,
+                ];
+            }
+            detections.push(ScrfdDetection {
+                bbox: [
+                    center_x - distance[0] * stride as f32,
+                    center_y - distance[1] * stride as f32,
+                    center_x + distance[2] * stride as f32,
+                    center_y + distance[3] * stride as f32,
+                ],
+                score: *score,
+                keypoints,
+            });
+        }
+    }
+    Ok(detections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Outputs of a 64x64 SCRFD input without any face above the threshold.
+    fn empty_outputs() -> Vec<(usize, Vec<f32>)> {
+        let mut outputs = vec![];
+        for columns in [1, 4, 10] {
+            for stride in SCRFD_STRIDES {
+                let cells = 64 / stride;
+                outputs.push((columns, vec![0.0; cells * cells * SCRFD_ANCHORS * columns]));
+            }
+        }
+        outputs
+    }
+
+    #[test]
+    fn decode_maps_distances_and_offsets_to_input_pixels() {
+        let mut outputs = empty_outputs();
+        // stride 16 has 4x4 locations, the second anchor of the location (2, 1)
+        let anchor = (4 + 2) * SCRFD_ANCHORS + 1;
+        outputs[1].1[anchor] = 0.9;
+        outputs[4].1[anchor * 4..anchor * 4 + 4].copy_from_slice(&[1.0, 0.5, 1.0, 2.0]);
+        outputs[7].1[anchor * 10..anchor * 10 + 10]
+            .copy_from_slice(&[-0.5, -0.5, 0.5, -0.5, 0.0, 0.0, -0.5, 0.5, 0.5, 0.5]);
+
+        let detections = decode_scrfd(&outputs, 64).unwrap();
+        assert_eq!(detections.len(), 1);
+        // anchor center (32, 16)
+        assert_eq!(detections[0].bbox, [16.0, 8.0, 48.0, 48.0]);
+        assert_eq!(detections[0].score, 0.9);
+        assert_eq!(
+            detections[0].keypoints,
+            [
+                [24.0, 8.0],
+                [40.0, 8.0],
+                [32.0, 16.0],
+                [24.0, 24.0],
+                [40.0, 24.0]
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_rejects_models_without_keypoints() {
+        let outputs: Vec<(usize, Vec<f32>)> = empty_outputs()
+            .into_iter()
+            .filter(|(columns, _)| *columns != 10)
+            .collect();
+        assert!(matches!(
+            decode_scrfd(&outputs, 64),
+            Err(PredictionError::OutputShape(_))
+        ));
+    }
+
+    #[test]
+    fn face_region_is_clipped_to_the_image() {
+        assert_eq!(
+            face_region(&[40.0, 40.0, 60.0, 60.0], (200, 100)),
+            (30, 30, 40, 40)
+        );
+        assert_eq!(
+            face_region(&[0.0, 70.0, 40.0, 100.0], (200, 100)),
+            (0, 45, 60, 55)
+        );
+    }
+}
@@ -1,39 +1,124 @@
 use std::{
-    error::Error,
+    collections::HashMap,
     fs,
+    io::BufWriter,
     path::{Path, PathBuf},
 };
 
 use arcface_predictor::ArcFacePredictor;
-use ndarray::{Array, Array1};
-use post_processor::{ArcFaceOutput, UltraOutput};
+use error::PredictionError;
+use face::{Face, ImageFaces};
+use gallery::{FileStamp, Gallery, GalleryRecord};
+use metric::{cosine_similarity, FaceMatcher};
+use ndarray::{Array, ArrayView1};
+use pipeline::Pipeline;
+use post_processor::{ArcFaceOutput, Bbox, UltraOutput};
 use ultra_image::UltraImage;
 use ultra_predictor::UltraPredictor;
 
-use rayon::prelude::*;
+use serde::Serialize;
+use tracing::{instrument, warn};
 
+pub mod annotation;
 pub mod arcface_image;
 pub mod arcface_predictor;
+pub mod clustering;
 pub mod config;
+pub mod error;
+pub mod face;
+pub mod face_alignment;
+pub mod face_crop;
+pub mod flat_index;
+pub mod gallery;
+pub mod hnsw_index;
+pub mod identity;
+pub mod incremental;
+pub mod landmark_predictor;
+pub mod logging;
+pub mod metric;
+pub mod output;
+pub mod pipeline;
 pub mod post_processor;
+pub mod predictor_pool;
+pub mod runtime;
 pub mod ultra_image;
 pub mod ultra_predictor;
+pub mod vector_index;
+pub mod verification;
+
+pub static DEFAULT_CHUNK_SIZE: usize = 10;
+
+/// Closest face of an image to a searched face.
+#[derive(Serialize, Clone, Debug)]
+pub struct SearchResult {
+    pub image_path: PathBuf,
+    /// Index of the face in the image, in order of detection confidence.
+    pub face_index: usize,
+    /// Bounding box in raw image pixel coordinates.
+    pub bbox: Bbox,
+    /// Detection confidence of the face.
+    pub confidence: f32,
+    /// Distance between the embeddings in the metric of the `FaceMatcher` used for the search.
+    pub distance: f32,
+    pub cosine_similarity: f32,
+    /// Whether the distance is within the match threshold.
+    pub is_match: bool,
+}
 
-static CHUNK_SIZE: usize = 10;
-
-pub fn process_file_path<'a>(
-    file_path: &'a Path,
+/// Detect and embed every face in the image at `file_path`.
+#[instrument(level = "debug", skip(ultra_predictor, arc_predictor))]
+pub fn process_file_path(
+    file_path: &Path,
     ultra_predictor: &UltraPredictor,
     arc_predictor: &ArcFacePredictor,
-) -> Result<(&'a Path, Vec<f32>), Box<dyn Error>> {
-    let ultra_image = UltraImage::new(file_path)?;
-    let ultra_output = &ultra_predictor.run(&ultra_image.image)?;
-    let arc_face_output = &arc_predictor.run(&ultra_image, &ultra_output.bbox_with_confidences)?;
-    let normalized_embedding = normalize_embedding(arc_face_output[0].embedding.clone());
-    Ok((&ultra_image.image_path, normalized_embedding))
+) -> Result<ImageFaces, PredictionError> {
+    let ultra_image = ultra_predictor.open_image(file_path)?;
+    let ultra_output = ultra_predictor.run(&ultra_image)?;
+    let arc_face_outputs = arc_predictor.run(&ultra_image, &ultra_output.bbox_with_confidences)?;
+    Ok(calculate_image_faces(
+        ultra_image,
+        ultra_output,
+        arc_face_outputs,
+    ))
+}
+
+/// Path below `output_folder` with the same location relative to it as `path` has relative to
+/// `input_root`, so outputs mirror the folder structure of the input.
+pub fn mirrored_path(output_folder: &Path, input_root: &Path, path: &Path) -> PathBuf {
+    let relative_path = path
+        .strip_prefix(input_root)
+        .ok()
+        .filter(|relative_path| !relative_path.as_os_str().is_empty())
+        .or_else(|| path.file_name().map(Path::new))
+        .unwrap_or(path);
+    output_folder.join(relative_path)
 }
 
-pub fn get_file_paths_from_folder(dir_path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+/// Write `path` with `write` into a temporary file next to it and rename that over `path` once it
+/// is complete and synced, so an interrupted or failed write keeps the previous file.
+pub(crate) fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<fs::File>) -> Result<(), PredictionError>,
+) -> Result<(), PredictionError> {
+    let mut temp_file_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_file_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_file_name);
+
+    let result = (|| -> Result<(), PredictionError> {
+        let mut writer = BufWriter::new(fs::File::create(&temp_path)?);
+        write(&mut writer)?;
+        let file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+pub fn get_file_paths_from_folder(dir_path: &Path) -> Result<Vec<PathBuf>, PredictionError> {
     let mut file_paths: Vec<PathBuf> = vec![];
     for dir_entry in fs::read_dir(dir_path)? {
         let entry = dir_entry?.path();
@@ -43,152 +128,107 @@ pub fn get_file_paths_from_folder(dir_path: &Path) -> Result<Vec<PathBuf>, Box<d
             file_paths.extend(get_file_paths_from_folder(&entry)?);
         }
     }
-    return Ok(file_paths);
-}
-
-pub fn process_file_paths<'a>(
-    file_paths: &'a Vec<PathBuf>,
-    ultra_predictor: &'a UltraPredictor,
-    // image_output_folder: &Path,
-    arc_predictor: &'a ArcFacePredictor,
-) -> Vec<(&'a Path, Vec<Vec<f32>>)> {
-    let mut images_with_embedding_result: Vec<(&Path, Vec<Vec<f32>>)> = vec![];
-    for file_paths in file_paths.chunks(CHUNK_SIZE) {
-        let images = par_get_ultra_images(file_paths);
-        let ultra_outputs = run_ultra_prediciton(&images, &ultra_predictor);
-        let images_with_arc_face_outputs =
-            run_arc_face_prediction(ultra_outputs, images, &arc_predictor);
-        let images_with_embeddings = calculate_embeddings(images_with_arc_face_outputs);
-        images_with_embedding_result.extend(images_with_embeddings)
-        // for mut image in images {
-        //     draw_boxes(&mut image, &ultra_predictor, &image_output_folder);
-        // }
-    }
-    return images_with_embedding_result;
-}
-
-fn par_get_ultra_images(file_paths: &[PathBuf]) -> Vec<UltraImage> {
-    file_paths
-        .into_par_iter()
-        .filter_map(|file_path| match UltraImage::new(file_path) {
-            Ok(image) => Some(image),
-            Err(error) => {
-                println!(
-                    "Unable to initalize file: {:?}, because of {}",
-                    &file_path,
-                    error.to_string()
-                );
-                return None;
-            }
-        })
-        .collect()
+    Ok(file_paths)
 }
 
-fn run_ultra_prediciton(
-    ultra_images: &Vec<UltraImage>,
-    predictor: &UltraPredictor,
-) -> Vec<UltraOutput> {
-    ultra_images
-        .into_iter()
-        .filter_map(|ultra_image| {
-            let ultra_output = predictor.run(&ultra_image.image);
-            match ultra_output {
-                Ok(ultra_output) => Some(ultra_output),
-                Err(error) => {
-                    println!("Unable to get run result because of {}", error.to_string());
-                    return None;
+/// Detect and embed the faces of `file_paths` with `pipeline`, adding them to `gallery` as they
+/// come out of it.
+pub fn process_file_paths_into_gallery(
+    file_paths: &[PathBuf],
+    pipeline: &Pipeline,
+    gallery: &mut Gallery,
+) -> Result<(), PredictionError> {
+    // stamp before processing so files changed in the meantime are picked up next time
+    let mut stamps = file_paths
+        .iter()
+        .map(|file_path| Ok((file_path.as_path(), FileStamp::from_path(file_path)?)))
+        .collect::<Result<HashMap<&Path, FileStamp>, PredictionError>>()?;
+    let model_id = &pipeline.arc_predictor().model_id;
+    let mut result = Ok(());
+    pipeline.run(file_paths, |image_faces| {
+        if result.is_err() {
+            return;
+        }
+        match image_faces {
+            Ok(image_faces) => {
+                if let Some(stamp) = stamps.remove(image_faces.image_path.as_path()) {
+                    gallery.mark_indexed(&image_faces.image_path, stamp);
                 }
+                result = gallery.extend(model_id, GalleryRecord::from_image_faces(image_faces));
             }
-        })
-        .collect()
-}
-
-fn run_arc_face_prediction<'a>(
-    ultra_outputs: Vec<UltraOutput>,
-    images: Vec<UltraImage<'a>>,
-    predictor: &ArcFacePredictor,
-) -> Vec<(UltraImage<'a>, Vec<ArcFaceOutput>)> {
-    images
-        .into_iter()
-        .zip(ultra_outputs.into_iter())
-        .filter_map(|(image, ultra_output)| {
-            let arc_output = predictor.run(&image, &ultra_output.bbox_with_confidences);
-            match arc_output {
-                Ok(arc_output) => Some((image, arc_output)),
-                Err(error) => {
-                    println!("Unable to get run result because of {}", error.to_string());
-                    return None;
+            // failed images are retried once they change
+            Err(failed_image) => {
+                warn!("Skipping image: {}", failed_image.error);
+                if let Some(stamp) = stamps.remove(failed_image.image_path.as_path()) {
+                    gallery.mark_failed(&failed_image.image_path, stamp);
                 }
             }
-        })
-        .collect()
+        }
+    });
+    result?;
+    Ok(())
 }
 
-fn calculate_embeddings<'a>(
-    images_with_arc_face_outputs: Vec<(UltraImage<'a>, Vec<ArcFaceOutput>)>,
-) -> Vec<(&Path, Vec<Vec<f32>>)> {
-    images_with_arc_face_outputs
+fn calculate_image_faces(
+    image: UltraImage,
+    ultra_output: UltraOutput,
+    arc_face_outputs: Vec<ArcFaceOutput>,
+) -> ImageFaces {
+    let faces = ultra_output
+        .bbox_with_confidences
         .into_iter()
-        .map(|(image, arc_face_outputs)| {
-            let embeddings: Vec<Vec<f32>> = arc_face_outputs
-                .into_iter()
-                .map(|output| normalize_embedding(output.embedding))
-                .collect();
-            (image.image_path, embeddings)
+        .zip(arc_face_outputs)
+        .map(|((bbox, confidence), output)| Face {
+            bbox,
+            confidence,
+            embedding: normalize_embedding(output.embedding),
         })
-        .collect()
+        .collect();
+    ImageFaces {
+        image_path: image.image_path.to_path_buf(),
+        faces,
+    }
 }
 
-fn normalize_embedding(embedding: Vec<f32>) -> Vec<f32> {
+pub(crate) fn normalize_embedding(embedding: Vec<f32>) -> Vec<f32> {
     let embedding = Array::from(embedding);
     let l2_norm = f32::sqrt(embedding.mapv(|v| v * v).sum());
     let normalized_embedding = embedding.mapv(|v| v / l2_norm);
     normalized_embedding.to_vec()
 }
 
-pub fn calculate_distances(
-    compare_embeddings: Vec<f32>,
-    images_with_embeddings: Vec<(&Path, Vec<Vec<f32>>)>,
-) -> Vec<(String, f32)> {
-    let mut path_with_dist: Vec<(String, f32)> = vec![];
-    let compare_embeddings_arr = Array::from(compare_embeddings);
-
-    for (image_path, image_embeddings) in images_with_embeddings {
-        let readable_path = image_path
-            .to_path_buf()
-            .into_os_string()
-            .into_string()
-            .unwrap();
-        let mut lowest_dist: f32 = 100.0;
-
-        for image_embedding in image_embeddings {
-            let image_embedding_arr = Array::from(image_embedding);
-            let dist = calculate_distance(image_embedding_arr, compare_embeddings_arr.clone());
-            if dist < lowest_dist {
-                lowest_dist = dist;
-            }
-        }
-        path_with_dist.push((readable_path, lowest_dist))
-    }
-    return path_with_dist;
-}
-
-fn calculate_distance(arr_a: Array1<f32>, arr_b: Array1<f32>) -> f32 {
-    let sub_res = &arr_a - &arr_b;
-    let sqr_res = sub_res.mapv(|v| v * v);
-    return sqr_res.sum();
+/// Find the closest face of every image to `compare_embedding`, sorted by increasing distance.
+/// Images without faces are left out.
+pub fn search_faces(
+    compare_embedding: &[f32],
+    images: &[ImageFaces],
+    matcher: &FaceMatcher,
+) -> Vec<SearchResult> {
+    let mut results: Vec<SearchResult> = images
+        .iter()
+        .filter_map(|image| {
+            image
+                .faces
+                .iter()
+                .enumerate()
+                .map(|(face_index, face)| {
+                    let distance = matcher.distance(&face.embedding, compare_embedding);
+                    SearchResult {
+                        image_path: image.image_path.clone(),
+                        face_index,
+                        bbox: face.bbox,
+                        confidence: face.confidence,
+                        distance,
+                        cosine_similarity: cosine_similarity(
+                            ArrayView1::from(&face.embedding),
+                            ArrayView1::from(compare_embedding),
+                        ),
+                        is_match: matcher.is_match(distance),
+                    }
+                })
+                .min_by(|a, b| a.distance.total_cmp(&b.distance))
+        })
+        .collect();
+    results.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    results
 }
-// fn draw_boxes(
-//     image: &mut UltraImage,
-//     predictor: &UltraPredictor,
-//     image_output_folder: &Path,
-// ) -> Result<(), Box<dyn Error>> {
-//     let output = predictor.run(&image.image)?;
-//     image
-//         .draw_bboxes(output.bbox_with_confidences, image_output_folder)
-//         .unwrap_or_else(|err| {
-//             println!("Error when trying to draw to image: {}", err.to_string());
-//             process::exit(1);
-//         });
-//     Ok(())
-// }
@@ -0,0 +1,30 @@
+use clap::ValueEnum;
+use ort::LoggingLevel;
+use serde::Deserialize;
+
+/// Minimum severity of the messages logged by ONNX Runtime.
+///
+/// The messages are forwarded to `tracing`, so they are also subject to the filter of the
+/// installed subscriber.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrtLogLevel {
+    Verbose,
+    Info,
+    #[default]
+    Warning,
+    Error,
+    Fatal,
+}
+
+impl From<OrtLogLevel> for LoggingLevel {
+    fn from(level: OrtLogLevel) -> Self {
+        match level {
+            OrtLogLevel::Verbose => LoggingLevel::Verbose,
+            OrtLogLevel::Info => LoggingLevel::Info,
+            OrtLogLevel::Warning => LoggingLevel::Warning,
+            OrtLogLevel::Error => LoggingLevel::Error,
+            OrtLogLevel::Fatal => LoggingLevel::Fatal,
+        }
+    }
+}
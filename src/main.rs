@@ -1,90 +1,799 @@
-mod post_processor;
-
 use face_prediction::{
-    arcface_predictor::ArcFacePredictor, calculate_distances, config::Config,
-    get_file_paths_from_folder, process_file_path, process_file_paths,
-    ultra_predictor::UltraPredictor,
+    annotation::{Annotator, ANNOTATED_FOLDER_NAME},
+    arcface_predictor::ArcFacePredictor,
+    clustering::{
+        cluster_faces, export_cluster_crops, ClusterOptions, CLUSTERS_FILE_NAME,
+        CLUSTER_CROPS_FOLDER_NAME,
+    },
+    config::{Command, Config, DEFAULT_LOG_LEVEL},
+    error::PredictionError,
+    face::{FaceSelection, ImageFaces},
+    face_crop::{write_crop_manifest, FaceCropper, CROPS_FOLDER_NAME, CROP_MANIFEST_FILE_NAME},
+    gallery::{Gallery, GALLERY_FILE_NAME},
+    get_file_paths_from_folder,
+    identity::identify_faces,
+    incremental::update_gallery,
+    landmark_predictor::LandmarkPredictor,
+    mirrored_path,
+    output::{
+        self, OutputFormat, OutputRecord, CLUSTER_RESULTS_FILE_NAME, IDENTIFY_RESULTS_FILE_NAME,
+        SEARCH_RESULTS_FILE_NAME, VERIFY_RESULTS_FILE_NAME,
+    },
+    pipeline::Pipeline,
+    predictor_pool::PredictorPool,
+    process_file_path, search_faces,
+    ultra_predictor::{TileOptions, UltraPredictor},
+    vector_index::{FaceIndex, IndexKind, DEFAULT_TOP_K, FACE_INDEX_FILE_NAME},
+    verification,
 };
-use ort::OrtError;
 use std::{
     env,
     fs::{self},
-    path::Path,
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
     process,
     time::Instant,
 };
+use tracing::{info, warn};
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
 
-static SESSION_THREADS: i16 = 10;
+static COMPARE_RESULTS_FILE_NAME: &str = "compare_results";
 
-fn main() -> Result<(), OrtError> {
+fn main() {
     let start = Instant::now();
     let args: Vec<String> = env::args().collect();
 
-    let config = Config::new(&args).unwrap_or_else(|err| {
-        println!("Problem parsing arguments: {}", err);
-        process::exit(1);
-    });
+    let config = Config::new(&args).unwrap_or_else(|err| err.exit());
+    init_logging(&config);
+
+    match &config.command {
+        Command::Detect { path } => detect(&config, Path::new(path)),
+        Command::Embed { image_path } => embed(&config, Path::new(image_path)),
+        Command::Index { folder_path } => {
+            let ultra_predictors = create_ultra_pool(&config);
+            let arc_predictors = create_arc_pool(&config);
+            index(
+                &config,
+                Path::new(folder_path),
+                &ultra_predictors,
+                &arc_predictors,
+            );
+        }
+        Command::Annotate { path } => annotate(&config, Path::new(path)),
+        Command::Crop { path } => crop(&config, Path::new(path)),
+        Command::Enroll {
+            name,
+            image_paths,
+            face_index,
+            replace,
+        } => enroll(
+            &config,
+            name,
+            image_paths,
+            face_selection(*face_index),
+            *replace,
+        ),
+        Command::Identify { folder_path } => {
+            identify(&config, folder_path.as_deref().map(Path::new))
+        }
+        Command::Cluster {
+            folder_path,
+            min_cluster_size,
+            max_neighbours,
+            vector_index,
+            export_crops,
+        } => cluster(
+            &config,
+            folder_path.as_deref().map(Path::new),
+            ClusterOptions {
+                min_cluster_size: *min_cluster_size,
+                max_neighbours: *max_neighbours,
+                index: vector_index.unwrap_or(IndexKind::Hnsw),
+            },
+            *export_crops,
+        ),
+        Command::Search {
+            test_case_path,
+            folder_path,
+            face_index,
+            vector_index,
+            top_k,
+        } => search(
+            &config,
+            Path::new(test_case_path),
+            folder_path.as_deref().map(Path::new),
+            face_selection(*face_index),
+            *vector_index,
+            *top_k,
+        ),
+        Command::Compare {
+            image_path,
+            other_image_path,
+            face_index,
+        } => compare(
+            &config,
+            Path::new(image_path),
+            Path::new(other_image_path),
+            face_selection(*face_index),
+        ),
+        Command::Verify {
+            image_path,
+            other_image_path,
+            face_index,
+            other_face_index,
+        } => verify(
+            &config,
+            Path::new(image_path),
+            Path::new(other_image_path),
+            face_selection(*face_index),
+            other_face_index.map(FaceSelection::Index),
+        ),
+    }
 
+    info!(elapsed = ?start.elapsed(), "finished");
+}
+
+/// Log to stderr so results printed to stdout stay machine readable. Spans are logged with their
+/// duration when they close, enable them with a debug filter.
+fn init_logging(config: &Config) {
+    let filter = match &config.log_level {
+        Some(log_level) => EnvFilter::new(log_level),
+        None => {
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_LEVEL))
+        }
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(io::stderr)
+        .init();
+}
+
+fn create_ultra_predictor(config: &Config) -> UltraPredictor {
     let ultra_model_path = Path::new(&config.ultra_model_path);
+    let ultra_predictor = UltraPredictor::new(ultra_model_path, &config.session_options())
+        .unwrap_or_else(|ort_err| {
+            eprintln!("Problem creating ultra onnx session: {}", ort_err);
+            process::exit(1)
+        })
+        .with_detection_options(config.detection_options())
+        .with_resize_mode(config.resize_mode);
+
+    let ultra_predictor = match (config.ultra_input_width, config.ultra_input_height) {
+        (Some(width), Some(height)) => ultra_predictor.with_input_size(width, height),
+        _ => ultra_predictor,
+    };
+    let ultra_predictor = match config.ultra_batch_size {
+        Some(ultra_batch_size) => ultra_predictor.with_max_batch_size(ultra_batch_size),
+        None => ultra_predictor,
+    };
+
+    if !config.tiled {
+        return ultra_predictor;
+    }
+    let tile_options = TileOptions {
+        tile_width: config
+            .tile_width
+            .unwrap_or(ultra_predictor.input_width as u32),
+        tile_height: config
+            .tile_height
+            .unwrap_or(ultra_predictor.input_height as u32),
+        overlap: config.tile_overlap,
+    };
+    ultra_predictor.with_tile_options(tile_options)
+}
+
+fn create_arc_predictor(config: &Config) -> ArcFacePredictor {
     let arc_face_model_path = Path::new(&config.arc_model_path);
-    let folder_path = Path::new(&config.folder_path);
-    let image_output_folder = Path::new(&config.result_folder);
-    let test_case_path = Path::new(&config.test_case_path);
-
-    let ultra_predictor =
-        UltraPredictor::new(ultra_model_path, SESSION_THREADS).unwrap_or_else(|ort_err| {
-            println!(
-                "Problem creating ultra onnx session: {}",
-                ort_err.to_string()
-            );
+    let mut face_arc_predictor =
+        ArcFacePredictor::new(arc_face_model_path, &config.session_options()).unwrap_or_else(
+            |ort_err| {
+                eprintln!("Problem creating arc onnx session: {}", ort_err);
+                process::exit(1)
+            },
+        );
+
+    if let Some(arc_batch_size) = config.arc_batch_size {
+        face_arc_predictor = face_arc_predictor.with_max_batch_size(arc_batch_size);
+    }
+    match &config.landmark_model_path {
+        Some(landmark_model_path) => {
+            let landmark_predictor =
+                create_landmark_predictor(config, Path::new(landmark_model_path));
+            face_arc_predictor = face_arc_predictor.with_landmark_predictor(landmark_predictor);
+        }
+        None => warn!("No landmark model, faces are aligned with the bounding box prior"),
+    }
+    face_arc_predictor
+}
+
+/// One Ultraface predictor per configured session, for the pipeline to detect in parallel.
+fn create_ultra_pool(config: &Config) -> PredictorPool<UltraPredictor> {
+    PredictorPool::new(config.ultra_sessions, || create_ultra_predictor(config))
+}
+
+/// One ArcFace predictor per configured session, for the pipeline to embed in parallel.
+fn create_arc_pool(config: &Config) -> PredictorPool<ArcFacePredictor> {
+    PredictorPool::new(config.arc_sessions, || create_arc_predictor(config))
+}
+
+fn create_landmark_predictor(config: &Config, landmark_model_path: &Path) -> LandmarkPredictor {
+    LandmarkPredictor::new(landmark_model_path, &config.session_options()).unwrap_or_else(
+        |ort_err| {
+            eprintln!("Problem creating landmark onnx session: {}", ort_err);
             process::exit(1)
-        });
+        },
+    )
+}
 
-    let face_arc_predictor = ArcFacePredictor::new(arc_face_model_path, SESSION_THREADS)
-        .unwrap_or_else(|ort_err| {
-            println!("Problem creating arc onnx session: {}", ort_err.to_string());
+fn detect(config: &Config, path: &Path) {
+    let ultra_predictor = create_ultra_predictor(config);
+
+    let file_paths = if path.is_dir() {
+        get_file_paths_from_folder(path).unwrap_or_else(|err| {
+            eprintln!("Problem getting files from folder: {:?}", err.to_string());
+            process::exit(1)
+        })
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    println!("\n\nFACE DETECTION RESULTS:");
+    for file_path in &file_paths {
+        let ultra_image = match ultra_predictor.open_image(file_path) {
+            Ok(ultra_image) => ultra_image,
+            Err(err) => {
+                eprintln!(
+                    "Unable to initalize file: {:?}, because of {}",
+                    file_path, err
+                );
+                continue;
+            }
+        };
+        match ultra_predictor.run(&ultra_image) {
+            Ok(ultra_output) => {
+                println!(
+                    "{} faces in {:?}",
+                    ultra_output.bbox_with_confidences.len(),
+                    file_path
+                );
+                for (bbox, confidence) in &ultra_output.bbox_with_confidences {
+                    println!("  {} at {:?}", confidence, bbox);
+                }
+            }
+            Err(err) => eprintln!("Unable to get run result because of {}", err),
+        }
+    }
+}
+
+fn annotate(config: &Config, path: &Path) {
+    let ultra_predictors = create_ultra_pool(config);
+    let arc_predictors = create_arc_pool(config);
+    let arc_predictor = arc_predictors.first();
+
+    let mut annotator = Annotator::new(config.face_matcher());
+    if let Some(annotation_font) = &config.annotation_font {
+        annotator = annotator
+            .with_font(Path::new(annotation_font))
+            .unwrap_or_else(|err| {
+                eprintln!("Problem loading annotation font: {}", err);
+                process::exit(1)
+            });
+    }
+
+    let result_folder = Path::new(&config.result_folder);
+    let gallery = if result_folder.join(GALLERY_FILE_NAME).exists() {
+        load_gallery(result_folder, arc_predictor)
+    } else {
+        warn!(
+            "No gallery in {:?}, faces are annotated without matches",
+            result_folder
+        );
+        Gallery::new(&arc_predictor.model_id)
+    };
+    // label with the enrolled identities if there are any, otherwise with the closest image
+    let gallery_images = gallery.images_with_faces();
+
+    let (input_root, file_paths) = input_file_paths(path);
+
+    // annotated images mirror the folder structure of the input
+    let output_folder = result_folder.join(ANNOTATED_FOLDER_NAME);
+    println!("\n\nFACE ANNOTATION RESULTS:");
+    let pipeline = Pipeline::pooled(
+        &ultra_predictors,
+        &arc_predictors,
+        config.pipeline_options(),
+    );
+    // images are annotated as soon as their faces are embedded
+    pipeline.run(&file_paths, |image_faces| {
+        let image_faces = match image_faces {
+            Ok(image_faces) => image_faces,
+            Err(failed_image) => {
+                warn!("Skipping image: {}", failed_image.error);
+                return;
+            }
+        };
+        let labels = if gallery.identities.is_empty() {
+            annotator.label_faces(&image_faces, &gallery_images)
+        } else {
+            annotator.identify_faces(&image_faces, &gallery.identities)
+        };
+        let output_path = mirrored_path(&output_folder, input_root, &image_faces.image_path);
+        match annotator.annotate(&image_faces.image_path, &labels, &output_path) {
+            Ok(()) => println!(
+                "{} faces in {:?} annotated to {:?}",
+                labels.len(),
+                image_faces.image_path,
+                output_path
+            ),
+            Err(err) => warn!("Problem annotating image: {}", err),
+        }
+    });
+}
+
+fn crop(config: &Config, path: &Path) {
+    let ultra_predictor = create_ultra_predictor(config);
+    let landmark_predictor = match (&config.landmark_model_path, config.aligned_crops) {
+        (Some(landmark_model_path), true) => Some(create_landmark_predictor(
+            config,
+            Path::new(landmark_model_path),
+        )),
+        _ => None,
+    };
+    let cropper = FaceCropper {
+        ultra_predictor: &ultra_predictor,
+        landmark_predictor: landmark_predictor.as_ref(),
+        options: config.crop_options(),
+    };
+
+    let (input_root, file_paths) = input_file_paths(path);
+    let output_folder = Path::new(&config.result_folder).join(CROPS_FOLDER_NAME);
+    let records = cropper.export(&file_paths, input_root, &output_folder);
+
+    let manifest_path = output_folder.join(CROP_MANIFEST_FILE_NAME);
+    fs::create_dir_all(&output_folder)
+        .and_then(|_| fs::File::create(&manifest_path))
+        .map_err(PredictionError::from)
+        .and_then(|file| write_crop_manifest(&records, BufWriter::new(file)))
+        .unwrap_or_else(|err| {
+            eprintln!("Problem writing manifest {:?}: {}", manifest_path, err);
             process::exit(1)
         });
 
-    fs::create_dir(image_output_folder).unwrap_or_else(|err| {
+    println!("\n\nFACE CROP RESULTS:");
+    println!(
+        "{} faces of {} images exported to {:?}",
+        records.len(),
+        file_paths.len(),
+        output_folder
+    );
+}
+
+/// Root folder and image paths of an image or folder argument.
+fn input_file_paths(path: &Path) -> (&Path, Vec<PathBuf>) {
+    if !path.is_dir() {
+        return (path.parent().unwrap_or(path), vec![path.to_path_buf()]);
+    }
+    let file_paths = get_file_paths_from_folder(path).unwrap_or_else(|err| {
+        eprintln!("Problem getting files from folder: {}", err);
+        process::exit(1)
+    });
+    (path, file_paths)
+}
+
+fn embed(config: &Config, image_path: &Path) {
+    let ultra_predictor = create_ultra_predictor(config);
+    let arc_predictor = create_arc_predictor(config);
+
+    let image_faces = embed_image(image_path, &ultra_predictor, &arc_predictor);
+
+    println!("\n\nFACE EMBEDDING RESULTS:");
+    if image_faces.faces.is_empty() {
+        println!("No faces found in {:?}", image_path);
+    }
+    for (face_index, face) in image_faces.faces.iter().enumerate() {
         println!(
-            "Unabel to create output dir: {}\ncontinuing..",
-            err.to_string()
-        )
+            "face {} with confidence {} at {:?}:\n{:?}",
+            face_index, face.confidence, face.bbox, face.embedding
+        );
+    }
+}
+
+fn embed_image(
+    image_path: &Path,
+    ultra_predictor: &UltraPredictor,
+    arc_predictor: &ArcFacePredictor,
+) -> ImageFaces {
+    process_file_path(image_path, ultra_predictor, arc_predictor).unwrap_or_else(|err| {
+        eprintln!("Problem embedding image {:?}: {}", image_path, err);
+        process::exit(1)
+    })
+}
+
+fn face_selection(face_index: Option<usize>) -> FaceSelection {
+    face_index.map_or(FaceSelection::Largest, FaceSelection::Index)
+}
+
+/// Embedding of the selected face of the probe image, exits if there is no such face.
+fn probe_embedding(
+    image_path: &Path,
+    selection: FaceSelection,
+    ultra_predictor: &UltraPredictor,
+    arc_predictor: &ArcFacePredictor,
+) -> Vec<f32> {
+    let image_faces = embed_image(image_path, ultra_predictor, arc_predictor);
+    let (face_index, face) = image_faces.select(selection).unwrap_or_else(|err| {
+        eprintln!("Problem selecting face: {}", err);
+        process::exit(1)
     });
+    if image_faces.faces.len() > 1 {
+        info!(
+            "Found {} faces in {:?}, using face {} at {:?}, choose another with --face-index",
+            image_faces.faces.len(),
+            image_path,
+            face_index,
+            face.bbox
+        );
+    }
+    face.embedding.clone()
+}
 
-    let file_paths = get_file_paths_from_folder(folder_path).unwrap_or_else(|err| {
-        println!("Problem getting files from folder: {:?}", err.to_string());
+/// Bring the gallery in `result_folder` up to date with `folder_path` and return it.
+fn index(
+    config: &Config,
+    folder_path: &Path,
+    ultra_predictors: &PredictorPool<UltraPredictor>,
+    arc_predictors: &PredictorPool<ArcFacePredictor>,
+) -> Gallery {
+    // the folder is embedded once, later runs only process files changed since then
+    let mut gallery = open_gallery(config, arc_predictors.first());
+    let gallery_path = Path::new(&config.result_folder).join(GALLERY_FILE_NAME);
+
+    let pipeline = Pipeline::pooled(ultra_predictors, arc_predictors, config.pipeline_options());
+    let changes = update_gallery(&mut gallery, folder_path, &pipeline).unwrap_or_else(|err| {
+        eprintln!("Problem indexing folder: {}", err);
         process::exit(1)
     });
+    info!(
+        "Indexed folder: {} added, {} modified, {} deleted",
+        changes.added.len(),
+        changes.modified.len(),
+        changes.deleted.len()
+    );
 
-    let images_with_embeddings = process_file_paths(
-        &file_paths,
-        &ultra_predictor,
-        // &image_output_folder,
-        &face_arc_predictor,
+    if !changes.is_empty() {
+        gallery.save(&gallery_path).unwrap_or_else(|err| {
+            eprintln!("Problem saving gallery {:?}: {}", gallery_path, err);
+            process::exit(1)
+        });
+    }
+    gallery
+}
+
+/// Load the gallery of the result folder, or create an empty one if there is none yet.
+fn open_gallery(config: &Config, arc_predictor: &ArcFacePredictor) -> Gallery {
+    let result_folder = Path::new(&config.result_folder);
+    fs::create_dir_all(result_folder).unwrap_or_else(|err| {
+        eprintln!("Unable to create output dir: {}", err);
+        process::exit(1)
+    });
+
+    if result_folder.join(GALLERY_FILE_NAME).exists() {
+        load_gallery(result_folder, arc_predictor)
+    } else {
+        Gallery::new(&arc_predictor.model_id)
+    }
+}
+
+fn load_gallery(result_folder: &Path, arc_predictor: &ArcFacePredictor) -> Gallery {
+    let gallery_path = result_folder.join(GALLERY_FILE_NAME);
+    Gallery::load(&gallery_path, &arc_predictor.model_id).unwrap_or_else(|err| {
+        eprintln!("Problem loading gallery {:?}: {}", gallery_path, err);
+        process::exit(1)
+    })
+}
+
+fn enroll(
+    config: &Config,
+    name: &str,
+    image_paths: &[String],
+    selection: FaceSelection,
+    replace: bool,
+) {
+    let ultra_predictor = create_ultra_predictor(config);
+    let arc_predictor = create_arc_predictor(config);
+
+    let mut gallery = open_gallery(config, &arc_predictor);
+    if replace {
+        gallery.identities.remove(name);
+    }
+
+    let mut enrolled = 0;
+    for image_path in image_paths.iter().map(Path::new) {
+        let image_faces = embed_image(image_path, &ultra_predictor, &arc_predictor);
+        let embedding = match image_faces.select(selection) {
+            Ok((_, face)) => face.embedding.clone(),
+            Err(err) => {
+                warn!("Skipping reference image: {}", err);
+                continue;
+            }
+        };
+        gallery
+            .enroll(&arc_predictor.model_id, name, image_path, embedding)
+            .unwrap_or_else(|err| {
+                eprintln!("Problem enrolling {:?}: {}", image_path, err);
+                process::exit(1)
+            });
+        enrolled += 1;
+    }
+    if enrolled == 0 {
+        eprintln!("No faces found in the reference images of {}", name);
+        process::exit(1)
+    }
+
+    let gallery_path = Path::new(&config.result_folder).join(GALLERY_FILE_NAME);
+    gallery.save(&gallery_path).unwrap_or_else(|err| {
+        eprintln!("Problem saving gallery {:?}: {}", gallery_path, err);
+        process::exit(1)
+    });
+
+    println!("\n\nFACE ENROLLMENT RESULTS:");
+    for identity in gallery.identities.values() {
+        println!(
+            "{} with {} reference images",
+            identity.name,
+            identity.reference_images.len()
+        );
+    }
+}
+
+fn identify(config: &Config, folder_path: Option<&Path>) {
+    let ultra_predictors = create_ultra_pool(config);
+    let arc_predictors = create_arc_pool(config);
+
+    let gallery = match folder_path {
+        Some(folder_path) => index(config, folder_path, &ultra_predictors, &arc_predictors),
+        None => load_gallery(Path::new(&config.result_folder), arc_predictors.first()),
+    };
+    if gallery.identities.is_empty() {
+        eprintln!("No identities enrolled, add them with the enroll command");
+        process::exit(1)
+    }
+
+    let results = identify_faces(
+        &gallery.images_with_faces(),
+        &gallery.identities,
+        &config.face_matcher(),
+    );
+    output_results(
+        config,
+        &results,
+        "FACE IDENTIFICATION RESULTS",
+        IDENTIFY_RESULTS_FILE_NAME,
+    );
+}
+
+fn cluster(
+    config: &Config,
+    folder_path: Option<&Path>,
+    cluster_options: ClusterOptions,
+    export_crops: bool,
+) {
+    let ultra_predictors = create_ultra_pool(config);
+    let arc_predictors = create_arc_pool(config);
+
+    let gallery = match folder_path {
+        Some(folder_path) => index(config, folder_path, &ultra_predictors, &arc_predictors),
+        None => load_gallery(Path::new(&config.result_folder), arc_predictors.first()),
+    };
+    let clustering = cluster_faces(
+        &gallery.images_with_faces(),
+        &config.face_matcher(),
+        &cluster_options,
     );
 
-    let (_, compare_embeddings) =
-        process_file_path(&test_case_path, &ultra_predictor, &face_arc_predictor).unwrap_or_else(
-            |err| {
-                println!(
-                    "Problem getting files from compare image: {:?}",
-                    err.to_string()
+    // the full assignment of faces to clusters is always saved next to the gallery
+    let result_folder = Path::new(&config.result_folder);
+    let clusters_path = result_folder.join(CLUSTERS_FILE_NAME);
+    fs::File::create(&clusters_path)
+        .map_err(PredictionError::from)
+        .and_then(|file| {
+            serde_json::to_writer_pretty(BufWriter::new(file), &clustering)
+                .map_err(PredictionError::from)
+        })
+        .unwrap_or_else(|err| {
+            eprintln!("Problem writing clusters {:?}: {}", clusters_path, err);
+            process::exit(1)
+        });
+
+    if export_crops {
+        let crops_folder = result_folder.join(CLUSTER_CROPS_FOLDER_NAME);
+        let crop_paths = export_cluster_crops(&clustering, &crops_folder, &config.crop_options())
+            .unwrap_or_else(|err| {
+                eprintln!(
+                    "Problem exporting cluster crops {:?}: {}",
+                    crops_folder, err
                 );
                 process::exit(1)
-            },
+            });
+        info!(
+            "{} cluster crops exported to {:?}",
+            crop_paths.len(),
+            crops_folder
         );
+    }
 
-    let mut path_with_dist = calculate_distances(compare_embeddings, images_with_embeddings);
+    info!(
+        "{} of {} faces in {} clusters, written to {:?}",
+        clustering
+            .clusters
+            .iter()
+            .map(|cluster| cluster.size)
+            .sum::<usize>(),
+        clustering.faces.len(),
+        clustering.clusters.len(),
+        clusters_path
+    );
+    output_results(
+        config,
+        &clustering.clusters,
+        "FACE CLUSTERING RESULTS",
+        CLUSTER_RESULTS_FILE_NAME,
+    );
+}
+
+fn search(
+    config: &Config,
+    test_case_path: &Path,
+    folder_path: Option<&Path>,
+    selection: FaceSelection,
+    vector_index: Option<IndexKind>,
+    top_k: Option<usize>,
+) {
+    let ultra_predictors = create_ultra_pool(config);
+    let arc_predictors = create_arc_pool(config);
+
+    let gallery = match folder_path {
+        Some(folder_path) => index(config, folder_path, &ultra_predictors, &arc_predictors),
+        None => load_gallery(Path::new(&config.result_folder), arc_predictors.first()),
+    };
+
+    let compare_embeddings = probe_embedding(
+        test_case_path,
+        selection,
+        ultra_predictors.first(),
+        arc_predictors.first(),
+    );
+
+    let results = match vector_index {
+        Some(kind) => {
+            let face_index = open_face_index(config, &gallery, kind);
+            face_index.search(
+                &compare_embeddings,
+                top_k.unwrap_or(DEFAULT_TOP_K),
+                &config.face_matcher(),
+            )
+        }
+        None => {
+            let mut results = search_faces(
+                &compare_embeddings,
+                &gallery.images_with_faces(),
+                &config.face_matcher(),
+            );
+            results.truncate(top_k.unwrap_or(results.len()));
+            results
+        }
+    };
+    output_results(
+        config,
+        &results,
+        "FACE RECOGNITION RESULTS",
+        SEARCH_RESULTS_FILE_NAME,
+    );
+}
+
+/// Load the vector index of the result folder and bring it up to date with `gallery`.
+fn open_face_index(config: &Config, gallery: &Gallery, kind: IndexKind) -> FaceIndex {
+    let face_index_path = Path::new(&config.result_folder).join(FACE_INDEX_FILE_NAME);
+    let mut face_index =
+        FaceIndex::open(&face_index_path, &gallery.model_id, kind).unwrap_or_else(|err| {
+            eprintln!("Problem loading face index {:?}: {}", face_index_path, err);
+            process::exit(1)
+        });
+
+    if face_index.sync(gallery) {
+        info!("{} faces in the {:?} index", face_index.len(), kind);
+        face_index.save(&face_index_path).unwrap_or_else(|err| {
+            eprintln!("Problem saving face index {:?}: {}", face_index_path, err);
+            process::exit(1)
+        });
+    }
+    face_index
+}
 
-    path_with_dist.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap());
+fn compare(config: &Config, image_path: &Path, other_image_path: &Path, selection: FaceSelection) {
+    let ultra_predictor = create_ultra_predictor(config);
+    let arc_predictor = create_arc_predictor(config);
 
-    println!("\n\nFACE RECOGNITION RESULTS:");
-    path_with_dist
-        .into_iter()
-        .for_each(|(path, dist)| println!("{} in {}", dist, path));
+    let compare_embeddings =
+        probe_embedding(image_path, selection, &ultra_predictor, &arc_predictor);
+    let other_image_faces = embed_image(other_image_path, &ultra_predictor, &arc_predictor);
 
-    println!("\nTotal time elapsed: {:?}", start.elapsed());
-    return Ok(());
+    if other_image_faces.faces.is_empty() {
+        warn!("No faces found in {:?}", other_image_path);
+    }
+    let results = search_faces(
+        &compare_embeddings,
+        &[other_image_faces],
+        &config.face_matcher(),
+    );
+    output_results(
+        config,
+        &results,
+        "FACE COMPARISON RESULT",
+        COMPARE_RESULTS_FILE_NAME,
+    );
+}
+
+fn verify(
+    config: &Config,
+    image_path: &Path,
+    other_image_path: &Path,
+    selection: FaceSelection,
+    other_selection: Option<FaceSelection>,
+) {
+    let ultra_predictor = create_ultra_predictor(config);
+    let arc_predictor = create_arc_predictor(config);
+
+    let result = verification::verify(
+        image_path,
+        other_image_path,
+        selection,
+        other_selection,
+        &ultra_predictor,
+        &arc_predictor,
+        &config.face_matcher(),
+    )
+    .unwrap_or_else(|err| {
+        eprintln!("Problem verifying {:?}: {}", other_image_path, err);
+        process::exit(1)
+    });
+    output_results(
+        config,
+        &[result],
+        "FACE VERIFICATION RESULT",
+        VERIFY_RESULTS_FILE_NAME,
+    );
+}
+
+/// Print `results` to stdout, or save them to the result folder with `--save-results`.
+fn output_results<R: OutputRecord>(config: &Config, results: &[R], title: &str, file_name: &str) {
+    if !config.save_results {
+        if config.output_format == OutputFormat::Text {
+            println!("\n\n{}:", title);
+        }
+        output::write_results(results, config.output_format, io::stdout().lock()).unwrap_or_else(
+            |err| {
+                eprintln!("Problem writing results: {}", err);
+                process::exit(1)
+            },
+        );
+        return;
+    }
+
+    let result_folder = Path::new(&config.result_folder);
+    let results_path = result_folder.join(format!(
+        "{}.{}",
+        file_name,
+        config.output_format.extension()
+    ));
+    fs::create_dir_all(result_folder)
+        .and_then(|_| fs::File::create(&results_path))
+        .map_err(PredictionError::from)
+        .and_then(|file| output::write_results(results, config.output_format, BufWriter::new(file)))
+        .unwrap_or_else(|err| {
+            eprintln!("Problem writing results to {:?}: {}", results_path, err);
+            process::exit(1)
+        });
+    info!("Saved {} results to {:?}", results.len(), results_path);
 }
@@ -0,0 +1,140 @@
+use clap::ValueEnum;
+use ndarray::ArrayView1;
+use serde::{Deserialize, Serialize};
+
+/// Default match threshold in cosine similarity. Not a measured operating point, see the README.
+pub static DEFAULT_COSINE_SIMILARITY_THRESHOLD: f32 = 0.4;
+
+/// How the distance between two embeddings is measured. Lower is closer for every metric, so
+/// cosine similarity is turned into the cosine distance `1 - similarity`.
+///
+/// For L2 normalized embeddings the metrics are monotonic in each other, the squared euclidean
+/// distance is `2 - 2 * similarity`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DistanceMetric {
+    /// `1 - cosine similarity`, in `[0, 2]`.
+    Cosine,
+    /// Euclidean distance between the normalized embeddings, in `[0, 2]`.
+    Euclidean,
+    /// Squared euclidean distance between the normalized embeddings, in `[0, 4]`.
+    #[default]
+    SquaredEuclidean,
+}
+
+impl DistanceMetric {
+    pub fn distance(&self, embedding: &[f32], other_embedding: &[f32]) -> f32 {
+        let (embedding, other_embedding) = (
+            ArrayView1::from(embedding),
+            ArrayView1::from(other_embedding),
+        );
+        match self {
+            DistanceMetric::Cosine => 1.0 - cosine_similarity(embedding, other_embedding),
+            DistanceMetric::Euclidean => squared_euclidean(embedding, other_embedding).sqrt(),
+            DistanceMetric::SquaredEuclidean => squared_euclidean(embedding, other_embedding),
+        }
+    }
+
+    /// Largest distance between two normalized embeddings.
+    pub fn max_distance(&self) -> f32 {
+        match self {
+            DistanceMetric::Cosine => 2.0,
+            DistanceMetric::Euclidean => 2.0,
+            DistanceMetric::SquaredEuclidean => 4.0,
+        }
+    }
+
+    /// Default match threshold, `DEFAULT_COSINE_SIMILARITY_THRESHOLD` expressed in this metric.
+    pub fn default_threshold(&self) -> f32 {
+        let squared_euclidean = 2.0 - 2.0 * DEFAULT_COSINE_SIMILARITY_THRESHOLD;
+        match self {
+            DistanceMetric::Cosine => 1.0 - DEFAULT_COSINE_SIMILARITY_THRESHOLD,
+            DistanceMetric::Euclidean => squared_euclidean.sqrt(),
+            DistanceMetric::SquaredEuclidean => squared_euclidean,
+        }
+    }
+}
+
+/// Decides whether two faces belong to the same person.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FaceMatcher {
+    pub metric: DistanceMetric,
+    /// Maximum distance in `metric` between faces of the same person.
+    pub threshold: f32,
+}
+
+impl FaceMatcher {
+    /// Matcher with the default threshold of `metric`.
+    pub fn new(metric: DistanceMetric) -> FaceMatcher {
+        FaceMatcher {
+            metric,
+            threshold: metric.default_threshold(),
+        }
+    }
+
+    pub fn with_threshold(mut self, threshold: f32) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn distance(&self, embedding: &[f32], other_embedding: &[f32]) -> f32 {
+        self.metric.distance(embedding, other_embedding)
+    }
+
+    pub fn is_match(&self, distance: f32) -> bool {
+        distance <= self.threshold
+    }
+}
+
+impl Default for FaceMatcher {
+    fn default() -> Self {
+        FaceMatcher::new(DistanceMetric::default())
+    }
+}
+
+/// Cosine similarity of two embeddings, in `[-1, 1]`. Embeddings without direction are
+/// dissimilar to everything.
+pub fn cosine_similarity(embedding: ArrayView1<f32>, other_embedding: ArrayView1<f32>) -> f32 {
+    let norms = embedding.dot(&embedding).sqrt() * other_embedding.dot(&other_embedding).sqrt();
+    if norms > 0.0 {
+        embedding.dot(&other_embedding) / norms
+    } else {
+        0.0
+    }
+}
+
+fn squared_euclidean(embedding: ArrayView1<f32>, other_embedding: ArrayView1<f32>) -> f32 {
+    let difference = &embedding - &other_embedding;
+    difference.dot(&difference)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_thresholds_match_the_cosine_threshold() {
+        assert!((DistanceMetric::Cosine.default_threshold() - 0.6).abs() < 1e-6);
+        assert!((DistanceMetric::Euclidean.default_threshold() - 1.2_f32.sqrt()).abs() < 1e-6);
+        assert!((DistanceMetric::SquaredEuclidean.default_threshold() - 1.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn metrics_agree_on_normalized_embeddings() {
+        // cosine similarity of 0.4, exactly at the default threshold
+        let (embedding, other_embedding) = ([1.0, 0.0], [0.4, 0.84_f32.sqrt()]);
+        for metric in [
+            DistanceMetric::Cosine,
+            DistanceMetric::Euclidean,
+            DistanceMetric::SquaredEuclidean,
+        ] {
+            let distance = metric.distance(&embedding, &other_embedding);
+            assert!(
+                (distance - metric.default_threshold()).abs() < 1e-5,
+                "{:?}",
+                metric
+            );
+            assert!(distance <= metric.max_distance());
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clustering::Cluster, error::PredictionError, identity::IdentifiedFace,
+    verification::VerificationResult, SearchResult,
+};
+
+pub static SEARCH_RESULTS_FILE_NAME: &str = "search_results";
+pub static IDENTIFY_RESULTS_FILE_NAME: &str = "identify_results";
+pub static VERIFY_RESULTS_FILE_NAME: &str = "verify_results";
+pub static CLUSTER_RESULTS_FILE_NAME: &str = "cluster_results";
+
+/// Format search and compare results are written in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// One human readable line per result, e.g. "distance in path".
+    #[default]
+    Text,
+    /// A single JSON array.
+    Json,
+    /// One JSON object per line.
+    Jsonl,
+    /// CSV with a header row, the bbox is split into the x1, y1, x2 and y2 columns.
+    Csv,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Text => "txt",
+            OutputFormat::Json => "json",
+            OutputFormat::Jsonl => "jsonl",
+            OutputFormat::Csv => "csv",
+        }
+    }
+}
+
+/// A result that can be written in every `OutputFormat`.
+pub trait OutputRecord: Serialize {
+    /// Line of the text format.
+    fn text_line(&self) -> String;
+    /// Column names of the CSV format.
+    fn csv_header() -> Vec<&'static str>;
+    /// Fields of the CSV format, in the order of `csv_header`.
+    fn csv_record(&self) -> Vec<String>;
+}
+
+impl OutputRecord for SearchResult {
+    fn text_line(&self) -> String {
+        // the original `distance in path` format, parsed by existing scripts
+        format!("{} in {}", self.distance, self.image_path.to_string_lossy())
+    }
+
+    fn csv_header() -> Vec<&'static str> {
+        vec![
+            "image_path",
+            "face_index",
+            "x1",
+            "y1",
+            "x2",
+            "y2",
+            "confidence",
+            "distance",
+            "cosine_similarity",
+            "is_match",
+        ]
+    }
+
+    fn csv_record(&self) -> Vec<String> {
+        vec![
+            self.image_path.to_string_lossy().into_owned(),
+            self.face_index.to_string(),
+            self.bbox[0].to_string(),
+            self.bbox[1].to_string(),
+            self.bbox[2].to_string(),
+            self.bbox[3].to_string(),
+            self.confidence.to_string(),
+            self.distance.to_string(),
+            self.cosine_similarity.to_string(),
+            self.is_match.to_string(),
+        ]
+    }
+}
+
+impl OutputRecord for IdentifiedFace {
+    fn text_line(&self) -> String {
+        format!(
+            "{} {} in {} face {}",
+            self.identity,
+            self.distance,
+            self.image_path.to_string_lossy(),
+            self.face_index
+        )
+    }
+
+    fn csv_header() -> Vec<&'static str> {
+        vec![
+            "image_path",
+            "face_index",
+            "x1",
+            "y1",
+            "x2",
+            "y2",
+            "confidence",
+            "identity",
+            "distance",
+            "cosine_similarity",
+            "is_match",
+        ]
+    }
+
+    fn csv_record(&self) -> Vec<String> {
+        vec![
+            self.image_path.to_string_lossy().into_owned(),
+            self.face_index.to_string(),
+            self.bbox[0].to_string(),
+            self.bbox[1].to_string(),
+            self.bbox[2].to_string(),
+            self.bbox[3].to_string(),
+            self.confidence.to_string(),
+            self.identity.clone(),
+            self.distance.to_string(),
+            self.cosine_similarity.to_string(),
+            self.is_match.to_string(),
+        ]
+    }
+}
+
+impl OutputRecord for VerificationResult {
+    fn text_line(&self) -> String {
+        let decision = if self.is_match {
+            "same person"
+        } else {
+            "different people"
+        };
+        format!(
+            "{} {}: {} face {} and {} face {}",
+            decision,
+            self.distance,
+            self.image_path.to_string_lossy(),
+            self.face_index,
+            self.other_image_path.to_string_lossy(),
+            self.other_face_index
+        )
+    }
+
+    fn csv_header() -> Vec<&'static str> {
+        vec![
+            "image_path",
+            "face_index",
+            "x1",
+            "y1",
+            "x2",
+            "y2",
+            "other_image_path",
+            "other_face_index",
+            "other_x1",
+            "other_y1",
+            "other_x2",
+            "other_y2",
+            "distance",
+            "cosine_similarity",
+            "is_match",
+        ]
+    }
+
+    fn csv_record(&self) -> Vec<String> {
+        vec![
+            self.image_path.to_string_lossy().into_owned(),
+            self.face_index.to_string(),
+            self.bbox[0].to_string(),
+            self.bbox[1].to_string(),
+            self.bbox[2].to_string(),
+            self.bbox[3].to_string(),
+            self.other_image_path.to_string_lossy().into_owned(),
+            self.other_face_index.to_string(),
+            self.other_bbox[0].to_string(),
+            self.other_bbox[1].to_string(),
+            self.other_bbox[2].to_string(),
+            self.other_bbox[3].to_string(),
+            self.distance.to_string(),
+            self.cosine_similarity.to_string(),
+            self.is_match.to_string(),
+        ]
+    }
+}
+
+impl OutputRecord for Cluster {
+    fn text_line(&self) -> String {
+        format!(
+            "cluster {}: {} faces, e.g. {} face {}",
+            self.id,
+            self.size,
+            self.representative_image_path.to_string_lossy(),
+            self.representative_face_index
+        )
+    }
+
+    fn csv_header() -> Vec<&'static str> {
+        vec![
+            "cluster_id",
+            "size",
+            "representative_image_path",
+            "representative_face_index",
+        ]
+    }
+
+    fn csv_record(&self) -> Vec<String> {
+        vec![
+            self.id.to_string(),
+            self.size.to_string(),
+            self.representative_image_path
+                .to_string_lossy()
+                .into_owned(),
+            self.representative_face_index.to_string(),
+        ]
+    }
+}
+
+/// Write `results` to `writer` in `format`.
+pub fn write_results<R: OutputRecord>(
+    results: &[R],
+    format: OutputFormat,
+    mut writer: impl Write,
+) -> Result<(), PredictionError> {
+    match format {
+        OutputFormat::Text => {
+            for result in results {
+                writeln!(writer, "{}", result.text_line())?;
+            }
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, results)?;
+            writeln!(writer)?;
+        }
+        OutputFormat::Jsonl => {
+            for result in results {
+                serde_json::to_writer(&mut writer, result)?;
+                writeln!(writer)?;
+            }
+        }
+        OutputFormat::Csv => write_csv(results, &mut writer).map_err(io::Error::from)?,
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_csv<R: OutputRecord>(results: &[R], writer: impl Write) -> Result<(), csv::Error> {
+    let mut csv_writer = csv::Writer::from_writer(writer);
+    csv_writer.write_record(R::csv_header())?;
+    for result in results {
+        csv_writer.write_record(result.csv_record())?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn search_results() -> Vec<SearchResult> {
+        vec![
+            SearchResult {
+                image_path: PathBuf::from("photos/a.jpg"),
+                face_index: 0,
+                bbox: [1.0, 2.0, 30.0, 40.5],
+                confidence: 0.9,
+                distance: 0.25,
+                cosine_similarity: 0.875,
+                is_match: true,
+            },
+            SearchResult {
+                image_path: PathBuf::from("photos/b, c.jpg"),
+                face_index: 1,
+                bbox: [0.0, 0.0, 10.0, 10.0],
+                confidence: 0.75,
+                distance: 1.5,
+                cosine_similarity: 0.25,
+                is_match: false,
+            },
+        ]
+    }
+
+    fn written(format: OutputFormat) -> String {
+        let mut output = vec![];
+        write_results(&search_results(), format, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn text_keeps_the_distance_in_path_format() {
+        assert_eq!(
+            written(OutputFormat::Text),
+            "0.25 in photos/a.jpg\n1.5 in photos/b, c.jpg\n"
+        );
+    }
+
+    #[test]
+    fn csv_has_a_header_and_quotes_fields() {
+        assert_eq!(
+            written(OutputFormat::Csv),
+            "image_path,face_index,x1,y1,x2,y2,confidence,distance,cosine_similarity,is_match\n\
+             photos/a.jpg,0,1,2,30,40.5,0.9,0.25,0.875,true\n\
+             \"photos/b, c.jpg\",1,0,0,10,10,0.75,1.5,0.25,false\n"
+        );
+    }
+
+    #[test]
+    fn jsonl_writes_one_object_per_line() {
+        let output = written(OutputFormat::Jsonl);
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["image_path"], "photos/b, c.jpg");
+        assert_eq!(lines[1]["is_match"], false);
+    }
+}
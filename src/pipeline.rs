@@ -0,0 +1,331 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{sync_channel, Receiver, TryRecvError},
+        Mutex,
+    },
+    thread,
+};
+
+use image::RgbImage;
+use tracing::debug_span;
+
+use crate::{
+    arcface_predictor::ArcFacePredictor,
+    error::PredictionError,
+    face::{Face, ImageFaces},
+    normalize_embedding,
+    post_processor::{ArcFaceOutput, UltraResult},
+    predictor_pool::PredictorPool,
+    ultra_image::UltraImage,
+    ultra_predictor::UltraPredictor,
+    DEFAULT_CHUNK_SIZE,
+};
+
+pub static DEFAULT_DECODE_WORKERS: usize = 4;
+pub static DEFAULT_DETECT_WORKERS: usize = 1;
+pub static DEFAULT_ALIGN_WORKERS: usize = 2;
+pub static DEFAULT_EMBED_WORKERS: usize = 1;
+
+/// Worker counts of the pipeline stages and the capacity of the queues between them.
+///
+/// Detect and embed workers are spread round robin over the sessions of their model and at
+/// least one worker runs per session. A session already runs on several intra-op threads, so one
+/// worker per session is usually enough.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PipelineOptions {
+    pub decode_workers: usize,
+    pub detect_workers: usize,
+    pub align_workers: usize,
+    pub embed_workers: usize,
+    /// Images buffered between two stages, bounds the memory held by decoded images.
+    pub queue_size: usize,
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        PipelineOptions {
+            decode_workers: DEFAULT_DECODE_WORKERS,
+            detect_workers: DEFAULT_DETECT_WORKERS,
+            align_workers: DEFAULT_ALIGN_WORKERS,
+            embed_workers: DEFAULT_EMBED_WORKERS,
+            queue_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+}
+
+/// Faces of an image aligned for embedding, the raw image is no longer needed at this point.
+struct AlignedImage {
+    image_path: PathBuf,
+    bboxes: UltraResult,
+    faces: Vec<RgbImage>,
+}
+
+/// An image that failed in one of the stages of the pipeline.
+#[derive(Debug)]
+pub struct FailedImage {
+    pub image_path: PathBuf,
+    pub error: PredictionError,
+}
+
+impl FailedImage {
+    fn new(image_path: &Path, error: PredictionError) -> FailedImage {
+        FailedImage {
+            image_path: image_path.to_path_buf(),
+            error,
+        }
+    }
+}
+
+type PipelineResult = Result<ImageFaces, FailedImage>;
+
+/// Streaming face pipeline: decode -> detect -> align -> embed -> sink.
+///
+/// Every stage runs on its own workers and hands images to the next stage through a bounded
+/// queue, so decoding, detection and embedding overlap and only a few images are held in memory
+/// at a time. Detection and embedding batch whatever is queued up to the batch size of their
+/// predictor.
+pub struct Pipeline<'a> {
+    /// Never empty.
+    ultra_predictors: &'a [UltraPredictor],
+    /// Never empty.
+    arc_predictors: &'a [ArcFacePredictor],
+    pub options: PipelineOptions,
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn new(
+        ultra_predictor: &'a UltraPredictor,
+        arc_predictor: &'a ArcFacePredictor,
+        options: PipelineOptions,
+    ) -> Pipeline<'a> {
+        Pipeline {
+            ultra_predictors: std::slice::from_ref(ultra_predictor),
+            arc_predictors: std::slice::from_ref(arc_predictor),
+            options,
+        }
+    }
+
+    /// Run detection and embedding on the sessions of predictor pools in parallel.
+    pub fn pooled(
+        ultra_predictors: &'a PredictorPool<UltraPredictor>,
+        arc_predictors: &'a PredictorPool<ArcFacePredictor>,
+        options: PipelineOptions,
+    ) -> Pipeline<'a> {
+        Pipeline {
+            ultra_predictors: ultra_predictors.predictors(),
+            arc_predictors: arc_predictors.predictors(),
+            options,
+        }
+    }
+
+    /// ArcFace predictor of the first session, all sessions embed with the same model.
+    pub fn arc_predictor(&self) -> &ArcFacePredictor {
+        &self.arc_predictors[0]
+    }
+
+    /// Process `file_paths`, handing every image to `sink` on the calling thread as soon as its
+    /// faces are embedded. Images arrive in the order they finish, not in the order of
+    /// `file_paths`, and images that fail in any stage arrive as errors.
+    pub fn run(&self, file_paths: &[PathBuf], mut sink: impl FnMut(PipelineResult)) {
+        let queue_size = self.options.queue_size.max(1);
+        let (path_sender, path_receiver) = sync_channel::<&Path>(queue_size);
+        let (decoded_sender, decoded_receiver) = sync_channel::<UltraImage>(queue_size);
+        let (detected_sender, detected_receiver) =
+            sync_channel::<(UltraImage, UltraResult)>(queue_size);
+        let (aligned_sender, aligned_receiver) = sync_channel::<AlignedImage>(queue_size);
+        let (result_sender, result_receiver) = sync_channel::<PipelineResult>(queue_size);
+
+        let path_receiver = Mutex::new(path_receiver);
+        let decoded_receiver = Mutex::new(decoded_receiver);
+        let detected_receiver = Mutex::new(detected_receiver);
+        let aligned_receiver = Mutex::new(aligned_receiver);
+
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                for file_path in file_paths {
+                    if path_sender.send(file_path).is_err() {
+                        return;
+                    }
+                }
+            });
+
+            for _ in 0..self.options.decode_workers.max(1) {
+                let (decoded_sender, result_sender) =
+                    (decoded_sender.clone(), result_sender.clone());
+                let path_receiver = &path_receiver;
+                scope.spawn(move || {
+                    while let Some(file_path) = next(path_receiver) {
+                        let _span = debug_span!("decode", image = ?file_path).entered();
+                        let sent = match self.ultra_predictors[0].open_image(file_path) {
+                            Ok(ultra_image) => decoded_sender.send(ultra_image).is_ok(),
+                            Err(error) => result_sender
+                                .send(Err(FailedImage::new(file_path, error)))
+                                .is_ok(),
+                        };
+                        if !sent {
+                            return;
+                        }
+                    }
+                });
+            }
+
+            let detect_workers = self.options.detect_workers.max(self.ultra_predictors.len());
+            for worker in 0..detect_workers {
+                let ultra_predictor = &self.ultra_predictors[worker % self.ultra_predictors.len()];
+                let (detected_sender, result_sender) =
+                    (detected_sender.clone(), result_sender.clone());
+                let decoded_receiver = &decoded_receiver;
+                scope.spawn(move || {
+                    let max_batch_size = ultra_predictor.max_batch_size;
+                    while let Some(ultra_images) =
+                        next_batch(decoded_receiver, max_batch_size, |_| 1)
+                    {
+                        let ultra_outputs = ultra_predictor.run_batch(&ultra_images);
+                        for (ultra_image, ultra_output) in
+                            ultra_images.into_iter().zip(ultra_outputs)
+                        {
+                            let sent = match ultra_output {
+                                Ok(ultra_output) => detected_sender
+                                    .send((ultra_image, ultra_output.bbox_with_confidences))
+                                    .is_ok(),
+                                Err(error) => result_sender
+                                    .send(Err(FailedImage::new(ultra_image.image_path, error)))
+                                    .is_ok(),
+                            };
+                            if !sent {
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+
+            for worker in 0..self.options.align_workers.max(1) {
+                // landmark sessions belong to the ArcFace predictors, spread over them too
+                let arc_predictor = &self.arc_predictors[worker % self.arc_predictors.len()];
+                let (aligned_sender, result_sender) =
+                    (aligned_sender.clone(), result_sender.clone());
+                let detected_receiver = &detected_receiver;
+                scope.spawn(move || {
+                    while let Some((ultra_image, bboxes)) = next(detected_receiver) {
+                        let _span = debug_span!("align", image = ?ultra_image.image_path).entered();
+                        let sent = match arc_predictor.align_faces(&ultra_image, &bboxes) {
+                            Ok(faces) => aligned_sender
+                                .send(AlignedImage {
+                                    image_path: ultra_image.image_path.to_path_buf(),
+                                    bboxes,
+                                    faces,
+                                })
+                                .is_ok(),
+                            Err(error) => result_sender
+                                .send(Err(FailedImage::new(ultra_image.image_path, error)))
+                                .is_ok(),
+                        };
+                        if !sent {
+                            return;
+                        }
+                    }
+                });
+            }
+
+            let embed_workers = self.options.embed_workers.max(self.arc_predictors.len());
+            for worker in 0..embed_workers {
+                let arc_predictor = &self.arc_predictors[worker % self.arc_predictors.len()];
+                let result_sender = result_sender.clone();
+                let aligned_receiver = &aligned_receiver;
+                scope.spawn(move || {
+                    let max_batch_size = arc_predictor.max_batch_size;
+                    while let Some(aligned_images) =
+                        next_batch(aligned_receiver, max_batch_size, |image| image.faces.len())
+                    {
+                        for result in embed(arc_predictor, aligned_images) {
+                            if result_sender.send(result).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+
+            // the stages stop once the senders of their input queue are gone
+            drop((
+                decoded_sender,
+                detected_sender,
+                aligned_sender,
+                result_sender,
+            ));
+            for result in result_receiver {
+                sink(result);
+            }
+        });
+    }
+}
+
+/// Embed the faces of `aligned_images` together with `ArcFacePredictor::embed_images`.
+fn embed(
+    arc_predictor: &ArcFacePredictor,
+    aligned_images: Vec<AlignedImage>,
+) -> Vec<PipelineResult> {
+    let faces: Vec<&[RgbImage]> = aligned_images
+        .iter()
+        .map(|aligned_image| aligned_image.faces.as_slice())
+        .collect();
+    let outputs = arc_predictor.embed_images(&faces);
+    aligned_images
+        .into_iter()
+        .zip(outputs)
+        .map(|(aligned_image, outputs)| match outputs {
+            Ok(outputs) => Ok(image_faces(aligned_image, outputs)),
+            Err(error) => Err(FailedImage::new(&aligned_image.image_path, error)),
+        })
+        .collect()
+}
+
+fn image_faces(aligned_image: AlignedImage, arc_face_outputs: Vec<ArcFaceOutput>) -> ImageFaces {
+    let faces = aligned_image
+        .bboxes
+        .into_iter()
+        .zip(arc_face_outputs)
+        .map(|((bbox, confidence), output)| Face {
+            bbox,
+            confidence,
+            embedding: normalize_embedding(output.embedding),
+        })
+        .collect();
+    ImageFaces {
+        image_path: aligned_image.image_path,
+        faces,
+    }
+}
+
+/// Next item of a queue shared by several workers, `None` once the queue is closed and empty.
+fn next<T>(receiver: &Mutex<Receiver<T>>) -> Option<T> {
+    receiver.lock().ok()?.recv().ok()
+}
+
+/// Wait for the next item of a queue, then take whatever else is already queued while the summed
+/// `size` stays within `max_size`. `None` once the queue is closed and empty.
+fn next_batch<T>(
+    receiver: &Mutex<Receiver<T>>,
+    max_size: usize,
+    size: impl Fn(&T) -> usize,
+) -> Option<Vec<T>> {
+    let receiver = receiver.lock().ok()?;
+    let first = receiver.recv().ok()?;
+    let mut batch_size = size(&first);
+    let mut batch = vec![first];
+    while batch_size < max_size {
+        match receiver.try_recv() {
+            Ok(item) => {
+                batch_size += size(&item);
+                batch.push(item);
+                if batch_size >= max_size {
+                    break;
+                }
+            }
+            Err(TryRecvError::Empty | TryRecvError::Disconnected) => break,
+        }
+    }
+    Some(batch)
+}
@@ -1,59 +1,182 @@
 use ndarray::s;
-use ort::{tensor::OrtOwnedTensor, OrtError, Value};
+use ort::{tensor::OrtOwnedTensor, Value};
+
+use crate::error::PredictionError;
 
 pub type Bbox = [f32; 4];
 pub type UltraResult = Vec<(Bbox, f32)>;
 
 /// Positive additive constant to avoid divide-by-zero.
 const EPS: f32 = 1.0e-7;
-static CONFIDENCE_THRESHOLD: f32 = 0.7;
+pub static DEFAULT_CONFIDENCE_THRESHOLD: f32 = 0.7;
+pub static DEFAULT_MAX_IOU: f32 = 0.5;
+
+/// Filtering applied to the raw Ultraface candidates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DetectionOptions {
+    /// Minimum confidence of a detected face.
+    pub confidence_threshold: f32,
+    /// Faces overlapping a more confident face with an IoU above this are suppressed.
+    pub max_iou: f32,
+}
+
+impl Default for DetectionOptions {
+    fn default() -> Self {
+        DetectionOptions {
+            confidence_threshold: DEFAULT_CONFIDENCE_THRESHOLD,
+            max_iou: DEFAULT_MAX_IOU,
+        }
+    }
+}
 
 pub struct UltraOutput {
     pub bbox_with_confidences: UltraResult,
 }
 
 impl UltraOutput {
-    pub fn new(outputs: Vec<Value>) -> Result<UltraOutput, OrtError> {
+    pub fn new(
+        outputs: Vec<Value>,
+        options: &DetectionOptions,
+    ) -> Result<UltraOutput, PredictionError> {
+        let mut ultra_outputs = UltraOutput::from_batch(outputs, 1, options)?;
+        Ok(ultra_outputs.remove(0))
+    }
+
+    /// Split the outputs of a run over `batch_size` images into one output per image, in the
+    /// order of the batch.
+    pub fn from_batch(
+        outputs: Vec<Value>,
+        batch_size: usize,
+        options: &DetectionOptions,
+    ) -> Result<Vec<UltraOutput>, PredictionError> {
+        if outputs.len() < 2 {
+            return Err(PredictionError::OutputShape(format!(
+                "expected confidence and bbox outputs from Ultraface, got {} outputs",
+                outputs.len()
+            )));
+        }
+
+        // confidences are BxNx2 with background and face scores, bboxes are BxNx4
         let output_0: OrtOwnedTensor<f32, _> = outputs[0].try_extract()?;
         let confidences_view = output_0.view();
-        let confidences = confidences_view.slice(s![0, .., 1]);
+        if confidences_view.ndim() != 3
+            || confidences_view.shape()[0] != batch_size
+            || confidences_view.shape()[1] == 0
+            || confidences_view.shape()[2] != 2
+        {
+            return Err(PredictionError::OutputShape(format!(
+                "expected Ultraface confidences of shape {}xNx2 with N > 0, got {:?}",
+                batch_size,
+                confidences_view.shape()
+            )));
+        }
 
         let output_1: OrtOwnedTensor<f32, _> = outputs[1].try_extract()?;
         let bbox_view = output_1.view();
-        let bbox_arr = bbox_view.to_slice().unwrap().to_vec();
-        let bboxes: Vec<Bbox> = bbox_arr.chunks(4).map(|x| x.try_into().unwrap()).collect();
+        let bbox_arr: Vec<f32> = bbox_view.iter().copied().collect();
+        let candidates = confidences_view.shape()[1];
+        if bbox_arr.len() != batch_size * candidates * 4 {
+            return Err(PredictionError::OutputShape(format!(
+                "expected {} Ultraface bboxes per image, got shape {:?}",
+                candidates,
+                bbox_view.shape()
+            )));
+        }
+
+        Ok(bbox_arr
+            .chunks_exact(candidates * 4)
+            .enumerate()
+            .map(|(image, image_bboxes)| {
+                let confidences: Vec<f32> = confidences_view
+                    .slice(s![image, .., 1])
+                    .iter()
+                    .copied()
+                    .collect();
+                UltraOutput::from_candidates(image_bboxes, &confidences, options)
+            })
+            .collect())
+    }
+
+    /// Select the boxes of one image from its `bbox_arr` of flattened candidate boxes and their
+    /// face `confidences`.
+    fn from_candidates(
+        bbox_arr: &[f32],
+        confidences: &[f32],
+        options: &DetectionOptions,
+    ) -> UltraOutput {
+        let bboxes: Vec<Bbox> = bbox_arr
+            .chunks_exact(4)
+            .map(|x| [x[0], x[1], x[2], x[3]])
+            .collect();
 
         let mut bboxes_with_confidences: Vec<_> = bboxes
             .iter()
             .zip(confidences.iter())
             .filter_map(|(bbox, confidence)| match confidence {
-                x if *x > CONFIDENCE_THRESHOLD => Some((bbox, confidence)),
+                x if *x > options.confidence_threshold => Some((bbox, confidence)),
                 _ => None,
             })
             .collect();
 
-        bboxes_with_confidences.sort_by(|a, b| a.1.partial_cmp(b.1).unwrap());
-        let selected_bboxes = non_maximum_suppression(bboxes_with_confidences, 0.5);
+        bboxes_with_confidences.sort_by(|a, b| a.1.total_cmp(b.1));
+        let selected_bboxes = non_maximum_suppression(bboxes_with_confidences, options.max_iou);
         let selected_bboxes_top = selected_bboxes.to_vec();
 
-        return Ok(UltraOutput {
+        UltraOutput {
             bbox_with_confidences: selected_bboxes_top,
-        });
+        }
     }
 }
 
+/// Merge detections from several passes over the same image, e.g. overlapping tiles, keeping
+/// the most confident of any boxes overlapping with an IoU above `max_iou`.
+pub fn merge_detections(detections: &UltraResult, max_iou: f32) -> UltraResult {
+    let mut bboxes_with_confidences: Vec<_> = detections
+        .iter()
+        .map(|(bbox, confidence)| (bbox, confidence))
+        .collect();
+
+    bboxes_with_confidences.sort_by(|a, b| a.1.total_cmp(b.1));
+    non_maximum_suppression(bboxes_with_confidences, max_iou)
+}
+
 pub struct ArcFaceOutput {
     pub embedding: Vec<f32>,
 }
 
 impl ArcFaceOutput {
-    pub fn new(outputs: Vec<Value>) -> Result<ArcFaceOutput, OrtError> {
-        let output_1: OrtOwnedTensor<f32, _> = outputs[0].try_extract()?;
+    pub fn new(outputs: Vec<Value>) -> Result<ArcFaceOutput, PredictionError> {
+        let mut arc_face_outputs = ArcFaceOutput::from_batch(outputs, 1)?;
+        Ok(arc_face_outputs.remove(0))
+    }
+
+    /// Split the output of a run over `batch_size` faces into one embedding per face.
+    pub fn from_batch(
+        outputs: Vec<Value>,
+        batch_size: usize,
+    ) -> Result<Vec<ArcFaceOutput>, PredictionError> {
+        let output_1: OrtOwnedTensor<f32, _> = outputs
+            .first()
+            .ok_or_else(|| {
+                PredictionError::OutputShape("expected an embedding output from ArcFace".into())
+            })?
+            .try_extract()?;
         let embeddings_view = output_1.view();
-        let embeddings_arr = embeddings_view.to_slice().unwrap().to_vec();
-        Ok(ArcFaceOutput {
-            embedding: embeddings_arr,
-        })
+        let embeddings_arr: Vec<f32> = embeddings_view.iter().copied().collect();
+        let embedding_size = embeddings_arr.len() / batch_size.max(1);
+        if embedding_size == 0 || embedding_size * batch_size.max(1) != embeddings_arr.len() {
+            return Err(PredictionError::OutputShape(format!(
+                "ArcFace returned {} values for a batch of {} faces",
+                embeddings_arr.len(),
+                batch_size
+            )));
+        }
+        Ok(embeddings_arr
+            .chunks_exact(embedding_size)
+            .map(|embedding| ArcFaceOutput {
+                embedding: embedding.to_vec(),
+            })
+            .collect())
     }
 }
 
@@ -96,7 +219,7 @@ fn non_maximum_suppression(
 }
 
 /// Calculate the intersection-over-union metric for two bounding boxes.
-fn iou(bbox_a: &Bbox, bbox_b: &Bbox) -> f32 {
+pub(crate) fn iou(bbox_a: &Bbox, bbox_b: &Bbox) -> f32 {
     // Calculate corner points of overlap box
     // If the boxes do not overlap, the corner-points will be ill defined, i.e. the top left
     // corner point will be below and to the right of the bottom right corner point. In this case,
@@ -0,0 +1,36 @@
+pub static DEFAULT_SESSIONS: usize = 1;
+
+/// Several predictors of the same model, each with its own onnx session.
+///
+/// One session runs one inference at a time on its intra-op threads, which leaves cores idle
+/// between runs and while images are decoded. A pool lets several workers run inference at the
+/// same time, each on its own session.
+pub struct PredictorPool<P> {
+    /// Never empty.
+    predictors: Vec<P>,
+}
+
+impl<P> PredictorPool<P> {
+    /// Create a pool of `size` predictors with `create`, a `size` of 0 creates a single one.
+    pub fn new(size: usize, mut create: impl FnMut() -> P) -> PredictorPool<P> {
+        PredictorPool {
+            predictors: (0..size.max(1)).map(|_| create()).collect(),
+        }
+    }
+
+    /// The first predictor, for single images and for settings shared by the whole pool.
+    pub fn first(&self) -> &P {
+        &self.predictors[0]
+    }
+
+    pub fn predictors(&self) -> &[P] {
+        &self.predictors
+    }
+}
+
+/// Intra-op threads of each of `sessions` sessions sharing a budget of `thread_budget` threads,
+/// at least one per session.
+pub fn threads_per_session(thread_budget: i16, sessions: usize) -> i16 {
+    let sessions = i16::try_from(sessions.max(1)).unwrap_or(i16::MAX);
+    (thread_budget / sessions).max(1)
+}
@@ -0,0 +1,136 @@
+use std::{
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+use clap::ValueEnum;
+use ort::{
+    CPUExecutionProviderOptions, Environment, ExecutionProvider, GraphOptimizationLevel, OrtError,
+    Session, SessionBuilder,
+};
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::{config::DEFAULT_SESSION_THREADS, error::PredictionError, logging::OrtLogLevel};
+
+pub static ORT_ENVIRONMENT_NAME: &str = "face-prediction";
+
+static ENVIRONMENT: OnceLock<Arc<Environment>> = OnceLock::new();
+
+/// The ONNX Runtime environment shared by every session of the process.
+///
+/// The environment holds the logger and the global thread state, so it is created once with the
+/// log level of the first call and reused afterwards.
+pub fn environment(log_level: OrtLogLevel) -> Result<Arc<Environment>, OrtError> {
+    if let Some(environment) = ENVIRONMENT.get() {
+        return Ok(environment.clone());
+    }
+    let environment = Environment::builder()
+        .with_name(ORT_ENVIRONMENT_NAME.to_string())
+        .with_execution_providers([ExecutionProvider::CPU(Default::default())])
+        .with_log_level(log_level.into())
+        .build()?
+        .into_arc();
+    // another thread may have created it in the meantime, keep the first one
+    Ok(ENVIRONMENT.get_or_init(|| environment).clone())
+}
+
+/// Graph optimizations applied when a model is loaded. Higher levels load slower but usually
+/// run faster.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OptimizationLevel {
+    #[default]
+    Disable,
+    /// Redundant node eliminations and constant folding.
+    Basic,
+    /// Also complex node fusions.
+    Extended,
+    /// Also layout optimizations.
+    All,
+}
+
+impl From<OptimizationLevel> for GraphOptimizationLevel {
+    fn from(level: OptimizationLevel) -> Self {
+        match level {
+            OptimizationLevel::Disable => GraphOptimizationLevel::Disable,
+            OptimizationLevel::Basic => GraphOptimizationLevel::Level1,
+            OptimizationLevel::Extended => GraphOptimizationLevel::Level2,
+            OptimizationLevel::All => GraphOptimizationLevel::Level3,
+        }
+    }
+}
+
+/// How the nodes of a model graph are scheduled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecutionMode {
+    /// One node at a time, each on the intra-op threads.
+    #[default]
+    Sequential,
+    /// Independent branches of the graph at the same time on the inter-op threads.
+    Parallel,
+}
+
+/// Options of the onnx sessions of all predictors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionOptions {
+    pub optimization_level: OptimizationLevel,
+    /// Threads running a single node.
+    pub intra_threads: i16,
+    /// Threads running independent nodes in parallel execution mode, `None` keeps the ONNX
+    /// Runtime default.
+    pub inter_threads: Option<i16>,
+    /// Reuse memory of the CPU allocator between runs instead of allocating per run.
+    pub memory_arena: bool,
+    pub execution_mode: ExecutionMode,
+    pub log_level: OrtLogLevel,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        SessionOptions {
+            optimization_level: OptimizationLevel::default(),
+            intra_threads: DEFAULT_SESSION_THREADS,
+            inter_threads: None,
+            memory_arena: false,
+            execution_mode: ExecutionMode::default(),
+            log_level: OrtLogLevel::default(),
+        }
+    }
+}
+
+impl SessionOptions {
+    pub fn with_intra_threads(mut self, intra_threads: i16) -> Self {
+        self.intra_threads = intra_threads;
+        self
+    }
+
+    /// Load the model at `model_path` into a session of the shared environment.
+    pub fn build_session(&self, model_path: &Path) -> Result<Session, PredictionError> {
+        debug!(model = ?model_path, options = ?self, "Creating onnx session");
+        let environment =
+            environment(self.log_level).map_err(PredictionError::model_load(model_path))?;
+        let cpu_options = CPUExecutionProviderOptions {
+            use_arena: self.memory_arena,
+        };
+        let builder = SessionBuilder::new(&environment)
+            .and_then(|builder| {
+                builder.with_execution_providers([ExecutionProvider::CPU(cpu_options)])
+            })
+            .and_then(|builder| builder.with_optimization_level(self.optimization_level.into()))
+            .and_then(|builder| builder.with_intra_threads(self.intra_threads))
+            .and_then(|builder| {
+                builder.with_parallel_execution(self.execution_mode == ExecutionMode::Parallel)
+            });
+        let builder = match self.inter_threads {
+            Some(inter_threads) => {
+                builder.and_then(|builder| builder.with_inter_threads(inter_threads))
+            }
+            None => builder,
+        };
+        builder
+            .and_then(|builder| builder.with_model_from_file(model_path))
+            .map_err(PredictionError::model_load(model_path))
+    }
+}
@@ -1,102 +1,201 @@
 use std::{
     fs::File,
+    io,
     path::{Path, PathBuf},
-    time::Instant,
 };
 
-use image::{imageops::FilterType, DynamicImage, ImageError, ImageFormat, Rgb, RgbImage};
+use clap::ValueEnum;
+use image::{
+    imageops::{self, FilterType},
+    DynamicImage, ImageError, ImageFormat, Rgb, RgbImage,
+};
 use imageproc::{drawing::draw_hollow_rect, rect::Rect};
+use serde::Deserialize;
+use tracing::instrument;
 
-use crate::{
-    post_processor::UltraResult,
-    ultra_predictor::{ULTRA_INPUT_HEIGHT, ULTRA_INPUT_WIDTH},
-};
+use crate::post_processor::{Bbox, UltraResult};
+
+/// How images are fitted to the fixed predictor input size.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeMode {
+    /// Scale the whole image into the input and pad the remaining border.
+    #[default]
+    Letterbox,
+    /// Scale the image to cover the input and center crop the overflow, faces outside the
+    /// crop are not detected.
+    Crop,
+}
+
+/// Padding color, the ImageNet mean which the predictor normalizes to zero.
+static LETTERBOX_COLOR: Rgb<u8> = Rgb([124, 116, 104]);
 
 pub struct UltraImage<'a> {
     pub image: RgbImage,
     pub raw_image: DynamicImage,
     pub image_path: &'a Path,
+    /// Scale from raw image pixels to predictor input pixels.
+    pub scale: f32,
+    /// Position of the raw image origin in predictor input pixels.
+    pub offset: (f32, f32),
 }
 
 impl UltraImage<'_> {
-    pub fn new(path: &Path) -> Result<UltraImage, ImageError> {
-        let start = Instant::now();
-
+    /// Open the image at `path` and fit it to the `width` x `height` input of the predictor.
+    #[instrument(level = "debug", skip(width, height, resize_mode))]
+    pub fn new(
+        path: &Path,
+        width: u32,
+        height: u32,
+        resize_mode: ResizeMode,
+    ) -> Result<UltraImage<'_>, ImageError> {
         let raw_image = image::open(path)?;
-        let image = raw_image
-            .resize_to_fill(
-                ULTRA_INPUT_WIDTH as u32,
-                ULTRA_INPUT_HEIGHT as u32,
-                FilterType::Triangle,
-            )
-            .to_rgb8();
+        let ultra_image = UltraImage::from_image(raw_image, path, width, height, resize_mode);
 
-        println!(
-            "Image initialization of {:?} took {:?}",
-            &path,
-            start.elapsed()
-        );
+        Ok(ultra_image)
+    }
+
+    /// Fit an already decoded image to the `width` x `height` input of the predictor.
+    pub fn from_image(
+        raw_image: DynamicImage,
+        path: &Path,
+        width: u32,
+        height: u32,
+        resize_mode: ResizeMode,
+    ) -> UltraImage<'_> {
+        let (raw_width, raw_height) = (raw_image.width() as f32, raw_image.height() as f32);
+
+        let scale = match resize_mode {
+            ResizeMode::Letterbox => f32::min(width as f32 / raw_width, height as f32 / raw_height),
+            ResizeMode::Crop => f32::max(width as f32 / raw_width, height as f32 / raw_height),
+        };
+        let scaled_width = ((raw_width * scale).round() as u32).max(1);
+        let scaled_height = ((raw_height * scale).round() as u32).max(1);
+        let offset_x = (width as i64 - scaled_width as i64) / 2;
+        let offset_y = (height as i64 - scaled_height as i64) / 2;
+
+        let scaled_image = raw_image
+            .resize_exact(scaled_width, scaled_height, FilterType::Triangle)
+            .to_rgb8();
+        let mut image = RgbImage::from_pixel(width, height, LETTERBOX_COLOR);
+        imageops::overlay(&mut image, &scaled_image, offset_x, offset_y);
 
-        return Ok(UltraImage {
+        UltraImage {
             raw_image,
             image,
             image_path: path,
-        });
+            scale,
+            offset: (offset_x as f32, offset_y as f32),
+        }
+    }
+
+    /// Map a bbox relative to the predictor input to raw image pixel coordinates, clamped to
+    /// the raw image.
+    pub fn bbox_to_raw(&self, bbox: &Bbox) -> Bbox {
+        let (width, height) = (self.image.width() as f32, self.image.height() as f32);
+        let (raw_width, raw_height) = (
+            self.raw_image.width() as f32,
+            self.raw_image.height() as f32,
+        );
+
+        let to_raw = |value: f32, size: f32, offset: f32, raw_size: f32| {
+            ((value * size - offset) / self.scale).clamp(0.0, raw_size)
+        };
+        [
+            to_raw(bbox[0], width, self.offset.0, raw_width),
+            to_raw(bbox[1], height, self.offset.1, raw_height),
+            to_raw(bbox[2], width, self.offset.0, raw_width),
+            to_raw(bbox[3], height, self.offset.1, raw_height),
+        ]
     }
 
+    /// Draw bboxes given in raw image pixel coordinates on the raw image and save it to
+    /// `output_folder`.
+    #[instrument(level = "debug", skip_all, fields(image = ?self.image_path))]
     pub fn draw_bboxes(
         &mut self,
         bbox_with_confidences: UltraResult,
         output_folder: &Path,
     ) -> Result<(), ImageError> {
-        let start = Instant::now();
-        self.image = draw_bboxes_on_image(
-            self.image.clone(),
-            bbox_with_confidences,
-            ULTRA_INPUT_WIDTH as u32,
-            ULTRA_INPUT_HEIGHT as u32,
-        );
+        let frame = draw_bboxes_on_image(self.raw_image.to_rgb8(), bbox_with_confidences);
 
+        let file_name = self.image_path.file_name().ok_or_else(|| {
+            ImageError::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} has no file name", self.image_path),
+            ))
+        })?;
         let mut output_path = PathBuf::from(output_folder);
-        let file_name = PathBuf::from(&self.image_path.file_name().expect("file_name not found"));
         output_path.push(file_name);
 
         File::create(&output_path)?;
         image::save_buffer_with_format(
             &output_path,
-            &self.image,
-            ULTRA_INPUT_WIDTH as u32,
-            ULTRA_INPUT_HEIGHT as u32,
+            &frame,
+            frame.width(),
+            frame.height(),
             image::ColorType::Rgb8,
             ImageFormat::Jpeg,
         )?;
-        println!("Drawing bboxes and to file took {:?}", start.elapsed());
         Ok(())
     }
 }
 
-/// Draw bounding boxes with confidence scores on the image.
-fn draw_bboxes_on_image(
-    mut frame: RgbImage,
-    bboxes_with_confidences: Vec<([f32; 4], f32)>,
-    width: u32,
-    height: u32,
-) -> RgbImage {
-    let (width, height) = (width as f32, height as f32);
-
+/// Draw bounding boxes given in pixel coordinates on the image.
+fn draw_bboxes_on_image(mut frame: RgbImage, bboxes_with_confidences: UltraResult) -> RgbImage {
     for (bbox, _) in bboxes_with_confidences.iter() {
         // Coordinates of top-left and bottom-right points
         // Coordinate frame basis is on the top left corner
-        let (x_tl, y_tl) = (bbox[0] * width, bbox[1] * height);
-        let (x_br, y_br) = (bbox[2] * width, bbox[3] * height);
+        let (x_tl, y_tl) = (bbox[0], bbox[1]);
+        let (x_br, y_br) = (bbox[2], bbox[3]);
         let rect_width = x_br - x_tl;
         let rect_height = y_br - y_tl;
 
-        let face_rect =
-            Rect::at(x_tl as i32, y_tl as i32).of_size(rect_width as u32, rect_height as u32);
+        let face_rect = Rect::at(x_tl as i32, y_tl as i32)
+            .of_size((rect_width as u32).max(1), (rect_height as u32).max(1));
 
         frame = draw_hollow_rect(&frame, face_rect, Rgb::from([0, 255, 0]));
     }
 
     frame
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 400 x 200 raw image fitted to a 200 x 200 input.
+    fn ultra_image(resize_mode: ResizeMode) -> UltraImage<'static> {
+        let raw_image = DynamicImage::ImageRgb8(RgbImage::new(400, 200));
+        UltraImage::from_image(raw_image, Path::new("photo.jpg"), 200, 200, resize_mode)
+    }
+
+    #[test]
+    fn letterbox_maps_bboxes_back_to_raw_pixels() {
+        let ultra_image = ultra_image(ResizeMode::Letterbox);
+        assert_eq!(ultra_image.scale, 0.5);
+        assert_eq!(ultra_image.offset, (0.0, 50.0));
+
+        assert_eq!(
+            ultra_image.bbox_to_raw(&[0.25, 0.5, 0.75, 0.75]),
+            [100.0, 100.0, 300.0, 200.0]
+        );
+        // boxes on the padding are clamped to the raw image
+        assert_eq!(
+            ultra_image.bbox_to_raw(&[0.0, 0.0, 1.0, 0.2]),
+            [0.0, 0.0, 400.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn crop_maps_bboxes_back_to_raw_pixels() {
+        let ultra_image = ultra_image(ResizeMode::Crop);
+        assert_eq!(ultra_image.scale, 1.0);
+        assert_eq!(ultra_image.offset, (-100.0, 0.0));
+
+        assert_eq!(
+            ultra_image.bbox_to_raw(&[0.0, 0.0, 0.5, 1.0]),
+            [100.0, 0.0, 200.0, 200.0]
+        );
+    }
+}
@@ -1,87 +1,484 @@
-use std::{path::Path, time::Instant};
+use std::path::Path;
 
 use image::RgbImage;
 use ndarray::{Array4, CowArray, IxDyn};
-use ort::{
-    Environment, ExecutionProvider, GraphOptimizationLevel, LoggingLevel, OrtError, Session,
-    SessionBuilder, Value,
-};
+use ort::{OrtError, Session, Value};
+use tracing::{debug, field, instrument, warn, Span};
 
-use crate::post_processor::UltraOutput;
+use crate::{
+    error::PredictionError,
+    post_processor::{merge_detections, Bbox, DetectionOptions, UltraOutput},
+    runtime::SessionOptions,
+    ultra_image::{ResizeMode, UltraImage},
+};
 
 pub struct UltraPredictor {
     pub name: String,
     pub session: Session,
+    pub detection_options: DetectionOptions,
+    pub input_width: usize,
+    pub input_height: usize,
+    pub resize_mode: ResizeMode,
+    pub tile_options: Option<TileOptions>,
+    /// Maximum number of images detected in one session run, 1 for models with a fixed batch
+    /// size of 1.
+    pub max_batch_size: usize,
+}
+
+/// Sliding window detection on raw image pixels, so faces too small to survive the downscale
+/// to the model input are still found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TileOptions {
+    pub tile_width: u32,
+    pub tile_height: u32,
+    /// Overlap of neighbouring tiles in pixels, faces smaller than this are fully inside at
+    /// least one tile.
+    pub overlap: u32,
 }
 
 pub static ULTRA_PREDICTOR_NAME: &str = "UltraPredictor";
-pub static ULTRA_INPUT_WIDTH: usize = 640;
-pub static ULTRA_INPUT_HEIGHT: usize = 480;
+/// Input size used when the model does not define a fixed one, matching the RFB-640 model.
+pub static DEFAULT_ULTRA_INPUT_WIDTH: usize = 640;
+pub static DEFAULT_ULTRA_INPUT_HEIGHT: usize = 480;
+pub static DEFAULT_TILE_OVERLAP: u32 = 128;
+pub static DEFAULT_ULTRA_BATCH_SIZE: usize = 8;
+/// Distance in pixels from a tile edge within which a box counts as cut by the edge.
+static TILE_EDGE_MARGIN: f32 = 2.0;
 
 impl UltraPredictor {
-    pub fn new(model_filepath: &Path, num_threads: i16) -> Result<UltraPredictor, OrtError> {
-        let start = Instant::now();
-
-        let environment = Environment::builder()
-            .with_name(ULTRA_PREDICTOR_NAME.to_string())
-            .with_execution_providers([ExecutionProvider::CPU(Default::default())])
-            .with_log_level(LoggingLevel::Verbose)
-            .build()?
-            .into_arc();
-
-        let session = SessionBuilder::new(&environment)?
-            .with_optimization_level(GraphOptimizationLevel::Disable)?
-            .with_intra_threads(num_threads)?
-            .with_model_from_file(&model_filepath)?;
-
-        println!(
-            "{} startup took {:?}",
-            ULTRA_PREDICTOR_NAME,
-            start.elapsed()
-        );
+    #[instrument(level = "debug", skip(session_options))]
+    pub fn new(
+        model_filepath: &Path,
+        session_options: &SessionOptions,
+    ) -> Result<UltraPredictor, PredictionError> {
+        let session = session_options.build_session(model_filepath)?;
+
+        // NCHW input, e.g. 640x480 for RFB-640 and 320x240 for RFB-320 and slim-320
+        let input_dimensions = session
+            .inputs
+            .first()
+            .map(|input| input.dimensions.clone())
+            .unwrap_or_default();
+        let input_height = input_dimensions.get(2).copied().flatten();
+        let input_width = input_dimensions.get(3).copied().flatten();
+        let batch_size = input_dimensions.first().copied().flatten();
+
         Ok(UltraPredictor {
             name: ULTRA_PREDICTOR_NAME.to_string(),
             session,
+            detection_options: DetectionOptions::default(),
+            input_width: input_width.map_or(DEFAULT_ULTRA_INPUT_WIDTH, |width| width as usize),
+            input_height: input_height.map_or(DEFAULT_ULTRA_INPUT_HEIGHT, |height| height as usize),
+            resize_mode: ResizeMode::default(),
+            tile_options: None,
+            max_batch_size: batch_size.map_or(DEFAULT_ULTRA_BATCH_SIZE, |size| size as usize),
         })
     }
 
-    pub fn run(&self, image: &RgbImage) -> Result<UltraOutput, OrtError> {
-        let start = Instant::now();
+    /// Detect up to `max_batch_size` images per session run in `run_batch`. Models with a fixed
+    /// batch dimension keep their batch size.
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        if self.has_dynamic_batch() {
+            self.max_batch_size = max_batch_size.max(1);
+        } else if max_batch_size != self.max_batch_size {
+            warn!(
+                "{} has a fixed batch size of {}, ignoring batch size {}",
+                ULTRA_PREDICTOR_NAME, self.max_batch_size, max_batch_size
+            );
+        }
+        self
+    }
+
+    /// Run tiled detection in `run` and `run_with_options`.
+    pub fn with_tile_options(mut self, tile_options: TileOptions) -> Self {
+        self.tile_options = Some(tile_options);
+        self
+    }
+
+    /// Set how images are fitted to the input size of the model.
+    pub fn with_resize_mode(mut self, resize_mode: ResizeMode) -> Self {
+        self.resize_mode = resize_mode;
+        self
+    }
+
+    /// Open the image at `path` preprocessed for this predictor.
+    pub fn open_image<'a>(&self, path: &'a Path) -> Result<UltraImage<'a>, PredictionError> {
+        UltraImage::new(
+            path,
+            self.input_width as u32,
+            self.input_height as u32,
+            self.resize_mode,
+        )
+        .map_err(PredictionError::image(path))
+    }
+
+    /// Set the input size for models with a dynamic input size. Models with a fixed input size
+    /// keep the size read from the model.
+    pub fn with_input_size(mut self, input_width: usize, input_height: usize) -> Self {
+        if self.has_dynamic_input_size() {
+            self.input_width = input_width.max(1);
+            self.input_height = input_height.max(1);
+        } else if (input_width, input_height) != (self.input_width, self.input_height) {
+            warn!(
+                "{} has a fixed input size of {}x{}, ignoring input size {}x{}",
+                ULTRA_PREDICTOR_NAME,
+                self.input_width,
+                self.input_height,
+                input_width,
+                input_height
+            );
+        }
+        self
+    }
+
+    /// Set the detection options used by `run`.
+    pub fn with_detection_options(mut self, detection_options: DetectionOptions) -> Self {
+        self.detection_options = detection_options;
+        self
+    }
+
+    /// Detect the faces in `ultra_image`, the bounding boxes of the output are in raw image pixel
+    /// coordinates.
+    pub fn run(&self, ultra_image: &UltraImage) -> Result<UltraOutput, PredictionError> {
+        self.run_with_options(ultra_image, &self.detection_options)
+    }
+
+    /// Detect the faces of several images, stacking up to `max_batch_size` images into one
+    /// session run. Returns one output per image in the order of `ultra_images`.
+    ///
+    /// Tiled detection and models with a batch size of 1 run the images one by one. If a batched
+    /// run fails, its images are run one by one so the error is attributed to the right image.
+    #[instrument(level = "debug", skip_all, fields(images = ultra_images.len()))]
+    pub fn run_batch(
+        &self,
+        ultra_images: &[UltraImage],
+    ) -> Vec<Result<UltraOutput, PredictionError>> {
+        if self.tile_options.is_some() || self.max_batch_size <= 1 {
+            return ultra_images
+                .iter()
+                .map(|ultra_image| self.run(ultra_image))
+                .collect();
+        }
+
+        let mut ultra_outputs: Vec<Result<UltraOutput, PredictionError>> = vec![];
+        for batch in ultra_images.chunks(self.max_batch_size) {
+            match self.run_single_batch(batch) {
+                Ok(batch_outputs) => ultra_outputs.extend(batch_outputs.into_iter().map(Ok)),
+                Err(error) => {
+                    warn!(
+                        "Batched detection failed, detecting images one by one: {}",
+                        error
+                    );
+                    ultra_outputs.extend(batch.iter().map(|ultra_image| self.run(ultra_image)));
+                }
+            }
+        }
+        ultra_outputs
+    }
+
+    /// Run detection with `detection_options` instead of the options of the predictor, so one
+    /// session can serve pipelines with different thresholds.
+    pub fn run_with_options(
+        &self,
+        ultra_image: &UltraImage,
+        detection_options: &DetectionOptions,
+    ) -> Result<UltraOutput, PredictionError> {
+        match &self.tile_options {
+            Some(tile_options) => self.run_tiled(ultra_image, detection_options, tile_options),
+            None => self.run_single(ultra_image, detection_options),
+        }
+    }
+
+    /// Detect faces on overlapping tiles of the raw image at native resolution as well as on the
+    /// whole image, merging the boxes with non-maximum-suppression.
+    ///
+    /// Boxes touching a tile edge inside the image are dropped, they are usually faces cut by the
+    /// edge. Their partial box would overlap the full box of the face too little to be merged
+    /// with it, and the full face is inside a neighbouring tile or found on the whole image.
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(image = ?ultra_image.image_path, tiles = field::Empty)
+    )]
+    pub fn run_tiled(
+        &self,
+        ultra_image: &UltraImage,
+        detection_options: &DetectionOptions,
+        tile_options: &TileOptions,
+    ) -> Result<UltraOutput, PredictionError> {
+        let raw_image = &ultra_image.raw_image;
+
+        // the whole image pass finds the faces larger than a tile
+        let mut detections = self
+            .run_single(ultra_image, detection_options)?
+            .bbox_with_confidences;
+
+        let xs = tile_origins(
+            raw_image.width(),
+            tile_options.tile_width,
+            tile_options.overlap,
+        );
+        let ys = tile_origins(
+            raw_image.height(),
+            tile_options.tile_height,
+            tile_options.overlap,
+        );
+        Span::current().record("tiles", xs.len() * ys.len());
+        if xs.len() > 1 || ys.len() > 1 {
+            for y in &ys {
+                for x in &xs {
+                    let tile = raw_image.crop_imm(
+                        *x,
+                        *y,
+                        tile_options.tile_width,
+                        tile_options.tile_height,
+                    );
+                    let tile_image = UltraImage::from_image(
+                        tile,
+                        ultra_image.image_path,
+                        self.input_width as u32,
+                        self.input_height as u32,
+                        self.resize_mode,
+                    );
+                    let tile_size = (tile_image.raw_image.width(), tile_image.raw_image.height());
+                    let tile_output = self.run_single(&tile_image, detection_options)?;
+                    detections.extend(
+                        tile_output
+                            .bbox_with_confidences
+                            .into_iter()
+                            .filter(|(bbox, _)| {
+                                !touches_interior_edge(
+                                    bbox,
+                                    (*x, *y),
+                                    tile_size,
+                                    (raw_image.width(), raw_image.height()),
+                                )
+                            })
+                            .map(|(bbox, confidence)| {
+                                let (x, y) = (*x as f32, *y as f32);
+                                (
+                                    [bbox[0] + x, bbox[1] + y, bbox[2] + x, bbox[3] + y],
+                                    confidence,
+                                )
+                            }),
+                    );
+                }
+            }
+        }
+
+        let bbox_with_confidences = merge_detections(&detections, detection_options.max_iou);
+        debug!(
+            faces = bbox_with_confidences.len(),
+            "merged tile detections"
+        );
+        Ok(UltraOutput {
+            bbox_with_confidences,
+        })
+    }
 
-        let image_tensor = self.get_image_tensor(&image);
+    #[instrument(level = "debug", skip_all, fields(image = ?ultra_image.image_path))]
+    fn run_single(
+        &self,
+        ultra_image: &UltraImage,
+        detection_options: &DetectionOptions,
+    ) -> Result<UltraOutput, PredictionError> {
+        let image_tensor = self.get_image_tensor(&[&ultra_image.image]);
         let image_input = self.get_image_input(&image_tensor)?;
         let raw_outputs = self.session.run(image_input)?;
-        let ultra_output = UltraOutput::new(raw_outputs)?;
-
-        println!(
-            "{} preprocessing and inference took {:?}",
-            ULTRA_PREDICTOR_NAME,
-            start.elapsed()
+        let mut ultra_output = UltraOutput::new(raw_outputs, detection_options)?;
+        for (bbox, _) in ultra_output.bbox_with_confidences.iter_mut() {
+            *bbox = ultra_image.bbox_to_raw(bbox);
+        }
+        debug!(
+            faces = ultra_output.bbox_with_confidences.len(),
+            "detected faces"
         );
+
         Ok(ultra_output)
     }
 
-    fn get_image_tensor(&self, image: &RgbImage) -> CowArray<f32, IxDyn> {
-        let image_tensor = CowArray::from(Array4::from_shape_fn(
-            (1, 3, ULTRA_INPUT_HEIGHT, ULTRA_INPUT_WIDTH),
-            |(_, c, y, x)| {
+    #[instrument(level = "debug", skip_all, fields(images = ultra_images.len()))]
+    fn run_single_batch(
+        &self,
+        ultra_images: &[UltraImage],
+    ) -> Result<Vec<UltraOutput>, PredictionError> {
+        let images: Vec<&RgbImage> = ultra_images
+            .iter()
+            .map(|ultra_image| &ultra_image.image)
+            .collect();
+        let image_tensor = self.get_image_tensor(&images);
+        let image_input = self.get_image_input(&image_tensor)?;
+        let raw_outputs = self.session.run(image_input)?;
+        let mut ultra_outputs =
+            UltraOutput::from_batch(raw_outputs, images.len(), &self.detection_options)?;
+        for (ultra_output, ultra_image) in ultra_outputs.iter_mut().zip(ultra_images) {
+            for (bbox, _) in ultra_output.bbox_with_confidences.iter_mut() {
+                *bbox = ultra_image.bbox_to_raw(bbox);
+            }
+        }
+
+        Ok(ultra_outputs)
+    }
+
+    fn has_dynamic_batch(&self) -> bool {
+        self.session
+            .inputs
+            .first()
+            .and_then(|input| input.dimensions.first().copied().flatten())
+            .is_none()
+    }
+
+    /// Whether both the height and width dimension of the NCHW input are dynamic.
+    fn has_dynamic_input_size(&self) -> bool {
+        !self.session.inputs.first().is_some_and(|input| {
+            input.dimensions.get(2).copied().flatten().is_some()
+                || input.dimensions.get(3).copied().flatten().is_some()
+        })
+    }
+
+    fn get_image_tensor(&self, images: &[&RgbImage]) -> CowArray<'_, f32, IxDyn> {
+        CowArray::from(Array4::from_shape_fn(
+            (images.len(), 3, self.input_height, self.input_width),
+            |(b, c, y, x)| {
                 let mean = [0.485, 0.456, 0.406][c];
                 let std = [0.229, 0.224, 0.225][c];
-                (image[(x as _, y as _)][c] as f32 / 255.0 - mean) / std
+                (images[b][(x as _, y as _)][c] as f32 / 255.0 - mean) / std
             },
         ))
-        .into_dyn();
-
-        return image_tensor;
+        .into_dyn()
     }
 
     fn get_image_input<'a>(
         &self,
         image_tensor: &'a CowArray<'a, f32, IxDyn>,
     ) -> Result<Vec<Value<'a>>, OrtError> {
-        let input_value = Value::from_array(self.session.allocator(), &image_tensor)?;
-        let input = vec![input_value];
+        let input_value = Value::from_array(self.session.allocator(), image_tensor)?;
+
+        Ok(vec![input_value])
+    }
+}
+
+/// Whether `bbox`, in pixel coordinates of the tile at `tile_origin`, reaches to within
+/// `TILE_EDGE_MARGIN` of a tile edge that is not also an edge of the image.
+fn touches_interior_edge(
+    bbox: &Bbox,
+    tile_origin: (u32, u32),
+    tile_size: (u32, u32),
+    image_size: (u32, u32),
+) -> bool {
+    let ((x, y), (width, height)) = (tile_origin, tile_size);
+    (x > 0 && bbox[0] <= TILE_EDGE_MARGIN)
+        || (y > 0 && bbox[1] <= TILE_EDGE_MARGIN)
+        || (x + width < image_size.0 && bbox[2] >= width as f32 - TILE_EDGE_MARGIN)
+        || (y + height < image_size.1 && bbox[3] >= height as f32 - TILE_EDGE_MARGIN)
+}
+
+/// Start positions of tiles of `tile_size` covering `size` with at least `overlap` between
+/// neighbours, the last tile is aligned with the end.
+fn tile_origins(size: u32, tile_size: u32, overlap: u32) -> Vec<u32> {
+    if size <= tile_size {
+        return vec![0];
+    }
+
+    let step = tile_size.saturating_sub(overlap).max(1);
+    let last = size - tile_size;
+    let mut origins: Vec<u32> = (0..last).step_by(step as usize).collect();
+    origins.push(last);
+    origins
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check that the tiles start at 0, end at `size`, are sorted and overlap by at least
+    /// `overlap`, so every face smaller than the overlap is fully inside one of them.
+    fn assert_covers(size: u32, tile_size: u32, overlap: u32) {
+        let origins = tile_origins(size, tile_size, overlap);
+        assert_eq!(origins[0], 0);
+        if size <= tile_size {
+            assert_eq!(origins, vec![0]);
+            return;
+        }
+        assert_eq!(*origins.last().unwrap() + tile_size, size);
+        for pair in origins.windows(2) {
+            assert!(pair[0] < pair[1], "{:?}", origins);
+            assert!(pair[0] + tile_size >= pair[1] + overlap, "{:?}", origins);
+        }
+    }
+
+    #[test]
+    fn tile_origins_cover_the_image() {
+        for size in [100, 640, 641, 1000, 1279, 1280, 1920, 4000] {
+            for (tile_size, overlap) in [(640, 128), (480, 128), (320, 0), (200, 199)] {
+                assert_covers(size, tile_size, overlap);
+            }
+        }
+    }
+
+    #[test]
+    fn tile_origins_edge_tiles() {
+        // smaller or equal to one tile, a single tile
+        assert_eq!(tile_origins(500, 640, 128), vec![0]);
+        assert_eq!(tile_origins(640, 640, 128), vec![0]);
+        // one pixel more needs a second tile aligned with the end
+        assert_eq!(tile_origins(641, 640, 128), vec![0, 1]);
+        // the last tile is aligned with the end rather than sticking out
+        assert_eq!(tile_origins(1200, 640, 128), vec![0, 512, 560]);
+        // exact fit does not repeat the last tile
+        assert_eq!(tile_origins(1152, 640, 128), vec![0, 512]);
+        // an overlap as large as the tile still advances
+        assert_eq!(tile_origins(12, 10, 10), vec![0, 1, 2]);
+    }
 
-        return Ok(input);
+    #[test]
+    fn boxes_cut_by_interior_tile_edges() {
+        let image_size = (1200, 1000);
+        let tile_size = (640, 480);
+        // tile in the top left corner, its right and bottom edges are inside the image
+        assert!(!touches_interior_edge(
+            &[0.0, 0.0, 50.0, 50.0],
+            (0, 0),
+            tile_size,
+            image_size
+        ));
+        assert!(touches_interior_edge(
+            &[600.0, 10.0, 639.0, 50.0],
+            (0, 0),
+            tile_size,
+            image_size
+        ));
+        assert!(touches_interior_edge(
+            &[10.0, 440.0, 50.0, 480.0],
+            (0, 0),
+            tile_size,
+            image_size
+        ));
+        assert!(!touches_interior_edge(
+            &[10.0, 10.0, 600.0, 470.0],
+            (0, 0),
+            tile_size,
+            image_size
+        ));
+        // tile in the bottom right corner, its left and top edges are inside the image
+        let origin = (560, 520);
+        assert!(touches_interior_edge(
+            &[1.0, 100.0, 50.0, 150.0],
+            origin,
+            tile_size,
+            image_size
+        ));
+        assert!(touches_interior_edge(
+            &[100.0, 0.0, 150.0, 50.0],
+            origin,
+            tile_size,
+            image_size
+        ));
+        assert!(!touches_interior_edge(
+            &[600.0, 440.0, 640.0, 480.0],
+            origin,
+            tile_size,
+            image_size
+        ));
     }
 }
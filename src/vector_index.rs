@@ -0,0 +1,372 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use ndarray::ArrayView1;
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use crate::{
+    error::PredictionError,
+    face::{Face, ImageFaces},
+    flat_index::FlatIndex,
+    gallery::{FileStamp, Gallery},
+    hnsw_index::{HnswIndex, HnswParams},
+    metric::{cosine_similarity, FaceMatcher},
+    post_processor::Bbox,
+    write_atomically, SearchResult,
+};
+
+/// Version of the on-disk face index format, bumped whenever the layout changes.
+pub static FACE_INDEX_VERSION: u32 = 2;
+pub static FACE_INDEX_FILE_NAME: &str = "face_index.bin";
+pub static DEFAULT_TOP_K: usize = 10;
+
+/// Nearest neighbour index over L2 normalized embeddings.
+///
+/// Indexes rank by squared euclidean distance. For normalized embeddings every `DistanceMetric`
+/// orders neighbours the same way, so one index serves all of them.
+pub trait VectorIndex {
+    /// Add an embedding, replacing the embedding of `id` if it is already indexed.
+    fn insert(&mut self, id: u64, embedding: Vec<f32>);
+    /// Remove the embedding of `id`, `false` if it was not indexed.
+    fn remove(&mut self, id: u64) -> bool;
+    /// Up to `k` closest embeddings to `query`, sorted by increasing distance.
+    fn search(&self, query: &[f32], k: usize) -> Vec<Neighbour>;
+    fn embedding(&self, id: u64) -> Option<&[f32]>;
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Neighbour {
+    pub id: u64,
+    /// Squared euclidean distance to the query.
+    pub distance: f32,
+}
+
+/// Vector index implementation used for searches.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexKind {
+    /// Exact search over every face.
+    #[default]
+    Flat,
+    /// Approximate search on an HNSW graph, for large galleries.
+    Hnsw,
+}
+
+/// One of the vector index implementations, chosen at runtime.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum AnyIndex {
+    Flat(FlatIndex),
+    Hnsw(HnswIndex),
+}
+
+impl AnyIndex {
+    pub fn new(kind: IndexKind) -> AnyIndex {
+        match kind {
+            IndexKind::Flat => AnyIndex::Flat(FlatIndex::new()),
+            IndexKind::Hnsw => AnyIndex::Hnsw(HnswIndex::new(HnswParams::default())),
+        }
+    }
+
+    pub fn kind(&self) -> IndexKind {
+        match self {
+            AnyIndex::Flat(_) => IndexKind::Flat,
+            AnyIndex::Hnsw(_) => IndexKind::Hnsw,
+        }
+    }
+
+    fn as_index(&self) -> &dyn VectorIndex {
+        match self {
+            AnyIndex::Flat(index) => index,
+            AnyIndex::Hnsw(index) => index,
+        }
+    }
+
+    fn as_index_mut(&mut self) -> &mut dyn VectorIndex {
+        match self {
+            AnyIndex::Flat(index) => index,
+            AnyIndex::Hnsw(index) => index,
+        }
+    }
+}
+
+impl VectorIndex for AnyIndex {
+    fn insert(&mut self, id: u64, embedding: Vec<f32>) {
+        self.as_index_mut().insert(id, embedding)
+    }
+
+    fn remove(&mut self, id: u64) -> bool {
+        self.as_index_mut().remove(id)
+    }
+
+    fn search(&self, query: &[f32], k: usize) -> Vec<Neighbour> {
+        self.as_index().search(query, k)
+    }
+
+    fn embedding(&self, id: u64) -> Option<&[f32]> {
+        self.as_index().embedding(id)
+    }
+
+    fn len(&self) -> usize {
+        self.as_index().len()
+    }
+}
+
+/// Face of the gallery stored in a `FaceIndex`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IndexedFace {
+    pub image_path: PathBuf,
+    /// Index of the face in the image, in order of detection confidence.
+    pub face_index: usize,
+    /// Bounding box in raw image pixel coordinates.
+    pub bbox: Bbox,
+    pub confidence: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct IndexedImage {
+    stamp: FileStamp,
+    ids: Vec<u64>,
+}
+
+/// Vector index over the faces of a gallery, kept up to date with it between runs.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FaceIndex {
+    pub version: u32,
+    pub model_id: String,
+    /// `build_id` of the gallery the index was built from.
+    pub gallery_build_id: u64,
+    index: AnyIndex,
+    faces: HashMap<u64, IndexedFace>,
+    images: BTreeMap<PathBuf, IndexedImage>,
+    next_id: u64,
+}
+
+impl FaceIndex {
+    pub fn new(model_id: &str, kind: IndexKind) -> FaceIndex {
+        FaceIndex {
+            version: FACE_INDEX_VERSION,
+            model_id: model_id.to_string(),
+            gallery_build_id: 0,
+            index: AnyIndex::new(kind),
+            faces: HashMap::new(),
+            images: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Load the face index at `path`. The index only holds data derived from the gallery, so a
+    /// missing index or one of another version, model or kind is replaced by an empty one.
+    pub fn open(
+        path: &Path,
+        model_id: &str,
+        kind: IndexKind,
+    ) -> Result<FaceIndex, PredictionError> {
+        if !path.exists() {
+            return Ok(FaceIndex::new(model_id, kind));
+        }
+        let reader = BufReader::new(fs::File::open(path)?);
+        let face_index: FaceIndex = bincode::deserialize_from(reader)?;
+        if face_index.version != FACE_INDEX_VERSION
+            || face_index.model_id != model_id
+            || face_index.index.kind() != kind
+        {
+            info!("Rebuilding face index {:?}", path);
+            return Ok(FaceIndex::new(model_id, kind));
+        }
+        Ok(face_index)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), PredictionError> {
+        write_atomically(path, |writer| Ok(bincode::serialize_into(writer, self)?))
+    }
+
+    pub fn kind(&self) -> IndexKind {
+        self.index.kind()
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Add the faces of an image, replacing the faces indexed for it before.
+    pub fn insert_image(&mut self, image_faces: &ImageFaces, stamp: FileStamp) {
+        self.remove_image(&image_faces.image_path);
+        let mut ids: Vec<u64> = vec![];
+        for (face_index, face) in image_faces.faces.iter().enumerate() {
+            let id = self.next_id;
+            self.next_id += 1;
+            self.index.insert(id, face.embedding.clone());
+            self.faces.insert(
+                id,
+                IndexedFace {
+                    image_path: image_faces.image_path.clone(),
+                    face_index,
+                    bbox: face.bbox,
+                    confidence: face.confidence,
+                },
+            );
+            ids.push(id);
+        }
+        self.images
+            .insert(image_faces.image_path.clone(), IndexedImage { stamp, ids });
+    }
+
+    /// Remove all faces of an image, `false` if the image was not indexed.
+    pub fn remove_image(&mut self, image_path: &Path) -> bool {
+        let indexed_image = match self.images.remove(image_path) {
+            Some(indexed_image) => indexed_image,
+            None => return false,
+        };
+        for id in indexed_image.ids {
+            self.index.remove(id);
+            self.faces.remove(&id);
+        }
+        true
+    }
+
+    /// Bring the index up to date with `gallery`, only touching images that were added, modified
+    /// or deleted since the last sync. An index of another build of the gallery is rebuilt, as
+    /// its faces can differ even where the file stamps match. Returns whether anything changed.
+    #[instrument(level = "debug", skip_all)]
+    pub fn sync(&mut self, gallery: &Gallery) -> bool {
+        let rebuilt = self.gallery_build_id != gallery.build_id;
+        if rebuilt {
+            if !self.images.is_empty() {
+                info!("Gallery was rebuilt, rebuilding face index");
+            }
+            *self = FaceIndex::new(&self.model_id, self.kind());
+            self.gallery_build_id = gallery.build_id;
+        }
+        let stale_paths: Vec<PathBuf> = self
+            .images
+            .iter()
+            .filter(|(image_path, indexed_image)| {
+                gallery.files.get(*image_path) != Some(&indexed_image.stamp)
+            })
+            .map(|(image_path, _)| image_path.clone())
+            .collect();
+        for image_path in &stale_paths {
+            self.remove_image(image_path);
+        }
+
+        let new_images: BTreeMap<&Path, FileStamp> = gallery
+            .files
+            .iter()
+            .filter(|(image_path, _)| !self.images.contains_key(*image_path))
+            .map(|(image_path, stamp)| (image_path.as_path(), *stamp))
+            .collect();
+        // only the faces of new images are copied out of the gallery
+        let mut new_faces: HashMap<&Path, Vec<Face>> = HashMap::new();
+        if !new_images.is_empty() {
+            for record in &gallery.records {
+                if new_images.contains_key(record.image_path.as_path()) {
+                    new_faces
+                        .entry(record.image_path.as_path())
+                        .or_default()
+                        .push(Face {
+                            bbox: record.bbox,
+                            confidence: record.confidence,
+                            embedding: record.embedding.clone(),
+                        });
+                }
+            }
+        }
+        for (image_path, stamp) in &new_images {
+            // files without faces are recorded too, so they are not checked again
+            let image_faces = ImageFaces {
+                image_path: image_path.to_path_buf(),
+                faces: new_faces.remove(image_path).unwrap_or_default(),
+            };
+            self.insert_image(&image_faces, *stamp);
+        }
+
+        if let AnyIndex::Hnsw(index) = &mut self.index {
+            if index.deleted() > index.len() {
+                index.compact();
+            }
+        }
+        rebuilt || !stale_paths.is_empty() || !new_images.is_empty()
+    }
+
+    /// Closest face of up to `k` images to `compare_embedding`, sorted by increasing distance in
+    /// the metric of `matcher`. Faces are looked up in the index, so with an approximate index
+    /// the results can miss a close image.
+    pub fn search(
+        &self,
+        compare_embedding: &[f32],
+        k: usize,
+        matcher: &FaceMatcher,
+    ) -> Vec<SearchResult> {
+        // an image can hold several of the nearest faces, widen the search until k images are
+        // found or the index has no more faces
+        let mut fetch = k.saturating_mul(4);
+        loop {
+            let neighbours = self.index.search(compare_embedding, fetch);
+            let exhausted = neighbours.len() < fetch || fetch >= self.index.len();
+            let results = self.closest_images(neighbours, compare_embedding, k, matcher);
+            if results.len() >= k || exhausted {
+                return results;
+            }
+            fetch = fetch.saturating_mul(2);
+        }
+    }
+
+    /// Closest face of up to `k` images among `neighbours`, which are sorted closest first.
+    fn closest_images(
+        &self,
+        neighbours: Vec<Neighbour>,
+        compare_embedding: &[f32],
+        k: usize,
+        matcher: &FaceMatcher,
+    ) -> Vec<SearchResult> {
+        let mut results: Vec<SearchResult> = vec![];
+        for neighbour in neighbours {
+            let (face, embedding) = match (
+                self.faces.get(&neighbour.id),
+                self.index.embedding(neighbour.id),
+            ) {
+                (Some(face), Some(embedding)) => (face, embedding),
+                _ => continue,
+            };
+            // neighbours come closest first, so the first face of every image is its best one
+            if results
+                .iter()
+                .any(|result| result.image_path == face.image_path)
+            {
+                continue;
+            }
+            let distance = matcher.distance(embedding, compare_embedding);
+            results.push(SearchResult {
+                image_path: face.image_path.clone(),
+                face_index: face.face_index,
+                bbox: face.bbox,
+                confidence: face.confidence,
+                distance,
+                cosine_similarity: cosine_similarity(
+                    ArrayView1::from(embedding),
+                    ArrayView1::from(compare_embedding),
+                ),
+                is_match: matcher.is_match(distance),
+            });
+            if results.len() == k {
+                break;
+            }
+        }
+        results
+    }
+}
@@ -0,0 +1,109 @@
+use std::path::{Path, PathBuf};
+
+use ndarray::ArrayView1;
+use serde::Serialize;
+use tracing::instrument;
+
+use crate::{
+    arcface_predictor::ArcFacePredictor,
+    error::PredictionError,
+    face::{Face, FaceSelection, ImageFaces},
+    metric::{cosine_similarity, FaceMatcher},
+    post_processor::Bbox,
+    process_file_path,
+    ultra_predictor::UltraPredictor,
+};
+
+/// Whether the faces compared in two images belong to the same person.
+#[derive(Serialize, Clone, Debug)]
+pub struct VerificationResult {
+    pub image_path: PathBuf,
+    /// Index of the compared face in the first image, in order of detection confidence.
+    pub face_index: usize,
+    /// Bounding box of the compared face in raw image pixel coordinates.
+    pub bbox: Bbox,
+    pub other_image_path: PathBuf,
+    pub other_face_index: usize,
+    pub other_bbox: Bbox,
+    /// Distance between the embeddings in the metric of the `FaceMatcher`.
+    pub distance: f32,
+    pub cosine_similarity: f32,
+    /// Whether the distance is within the match threshold.
+    pub is_match: bool,
+}
+
+/// Detect and embed the faces of both images and verify them with `verify_faces`.
+#[instrument(level = "debug", skip(ultra_predictor, arc_predictor, matcher))]
+pub fn verify(
+    image_path: &Path,
+    other_image_path: &Path,
+    selection: FaceSelection,
+    other_selection: Option<FaceSelection>,
+    ultra_predictor: &UltraPredictor,
+    arc_predictor: &ArcFacePredictor,
+    matcher: &FaceMatcher,
+) -> Result<VerificationResult, PredictionError> {
+    let image_faces = process_file_path(image_path, ultra_predictor, arc_predictor)?;
+    let other_image_faces = process_file_path(other_image_path, ultra_predictor, arc_predictor)?;
+    verify_faces(
+        &image_faces,
+        &other_image_faces,
+        selection,
+        other_selection,
+        matcher,
+    )
+}
+
+/// Compare the selected face of `image_faces` with a face of `other_image_faces`. Without
+/// `other_selection` the face of the other image closest to the selected face is used, so group
+/// photos can be verified against a portrait.
+pub fn verify_faces(
+    image_faces: &ImageFaces,
+    other_image_faces: &ImageFaces,
+    selection: FaceSelection,
+    other_selection: Option<FaceSelection>,
+    matcher: &FaceMatcher,
+) -> Result<VerificationResult, PredictionError> {
+    let (face_index, face) = image_faces.select(selection)?;
+    let (other_face_index, other_face) = match other_selection {
+        Some(other_selection) => other_image_faces.select(other_selection)?,
+        None => closest_face(face, other_image_faces, matcher)?,
+    };
+
+    let distance = matcher.distance(&face.embedding, &other_face.embedding);
+    Ok(VerificationResult {
+        image_path: image_faces.image_path.clone(),
+        face_index,
+        bbox: face.bbox,
+        other_image_path: other_image_faces.image_path.clone(),
+        other_face_index,
+        other_bbox: other_face.bbox,
+        distance,
+        cosine_similarity: cosine_similarity(
+            ArrayView1::from(&face.embedding),
+            ArrayView1::from(&other_face.embedding),
+        ),
+        is_match: matcher.is_match(distance),
+    })
+}
+
+fn closest_face<'a>(
+    face: &Face,
+    image_faces: &'a ImageFaces,
+    matcher: &FaceMatcher,
+) -> Result<(usize, &'a Face), PredictionError> {
+    image_faces
+        .faces
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| {
+            let distance_a = matcher.distance(&face.embedding, &a.embedding);
+            let distance_b = matcher.distance(&face.embedding, &b.embedding);
+            distance_a.total_cmp(&distance_b)
+        })
+        .ok_or_else(|| PredictionError::NoFace {
+            image_path: image_faces.image_path.clone(),
+            face_index: None,
+            face_count: 0,
+        })
+}